@@ -0,0 +1,293 @@
+//! Inclusion proofs of past blocks in NEAR's block merkle tree.
+//!
+//! Every block header commits to the hashes of all the blocks before it through
+//! `block_merkle_root`, the root of an append-only tree whose leaves are the blocks
+//! in ordinal order. Genesis has ordinal 1, so the root of a block with ordinal `n`
+//! covers `n - 1` blocks, and a block is the leaf `ordinal - 1`.
+//!
+//! The tree has the layout of NEAR's `merklize`, so knowing the number of blocks,
+//! [`PathPositions`] places every node of a path exactly. Nodes are cached at those
+//! positions, and the ones covering a full power of two of blocks never change as
+//! the chain grows, so they are kept when the head moves forward.
+
+use crate::{
+    positioned::PositionedTree, BatchProofError, BatchReport, CacheCapacity, CryptoHash,
+    HashCounts, HostFunctions, LeafIndex, MerklePath, ProofReport,
+};
+
+/// Verifies that blocks, given by their ordinal, are part of the block merkle tree
+/// of a trusted head.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlockMerkleVerifier<HF: HostFunctions> {
+    tree: PositionedTree<HF>,
+    /// kept apart from the size of the tree, which may not fit a `usize`
+    num_blocks: u64,
+}
+
+impl<HF: HostFunctions> BlockMerkleVerifier<HF> {
+    /// Creates a verifier for the `block_merkle_root` of a head, which commits to
+    /// `num_blocks` blocks, i.e. the ordinal of the head minus one.
+    pub fn new(block_merkle_root: CryptoHash, num_blocks: u64) -> Self {
+        Self::with_capacity(block_merkle_root, num_blocks, CacheCapacity::Unbounded)
+    }
+
+    /// Same as [`Self::new`], with a cache that never grows past `capacity`.
+    pub fn with_capacity(
+        block_merkle_root: CryptoHash,
+        num_blocks: u64,
+        capacity: CacheCapacity,
+    ) -> Self {
+        Self {
+            tree: PositionedTree::with_capacity(block_merkle_root, tree_size(num_blocks), capacity),
+            num_blocks,
+        }
+    }
+
+    /// Root the blocks are verified against.
+    pub fn block_merkle_root(&self) -> CryptoHash {
+        self.tree.root
+    }
+
+    /// Number of blocks the root commits to.
+    pub fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    /// Number of nodes currently cached.
+    pub fn cache_len(&self) -> usize {
+        self.tree.cached_nodes.len()
+    }
+
+    /// Verifies that `block_hash` is the block with ordinal `block_ordinal`.
+    pub fn verify(
+        &mut self,
+        block_ordinal: u64,
+        block_hash: CryptoHash,
+        proof: &MerklePath,
+    ) -> Result<(), BatchProofError> {
+        self.commit_block(block_ordinal, block_hash, proof, &mut HashCounts::default())
+            .map(|_| ())
+    }
+
+    /// Verifies a batch of `(block_ordinal, block_hash, proof)`, stopping at the
+    /// first block that fails.
+    pub fn verify_all<'a>(
+        &mut self,
+        blocks: impl IntoIterator<Item = (u64, CryptoHash, &'a MerklePath)>,
+    ) -> Result<(), BatchProofError> {
+        blocks
+            .into_iter()
+            .try_for_each(|(block_ordinal, block_hash, proof)| {
+                self.verify(block_ordinal, block_hash, proof)
+            })
+    }
+
+    /// Verifies every `(block_ordinal, block_hash, proof)` of a batch, without
+    /// stopping at the first failure.
+    pub fn verify_batch<'a>(
+        &mut self,
+        blocks: impl IntoIterator<Item = (u64, CryptoHash, &'a MerklePath)>,
+    ) -> BatchReport {
+        let proofs = blocks
+            .into_iter()
+            .map(|(block_ordinal, block_hash, proof)| {
+                let mut counts = HashCounts::default();
+                let result = self.commit_block(block_ordinal, block_hash, proof, &mut counts);
+                ProofReport::new(result, counts.computed, counts.cached)
+            })
+            .collect();
+        BatchReport { proofs }
+    }
+
+    /// Moves to a head further down the same chain, with a root committing to
+    /// `num_blocks` blocks.
+    ///
+    /// Cached nodes covering a full power of two of blocks are kept, as those blocks
+    /// do not change; the others depend on the number of blocks and are dropped. The
+    /// caller must make sure both heads are on the same chain, e.g. by verifying the
+    /// new head, or start over with a new verifier.
+    pub fn set_head(&mut self, block_merkle_root: CryptoHash, num_blocks: u64) {
+        self.tree.resize(block_merkle_root, tree_size(num_blocks));
+        self.num_blocks = num_blocks;
+    }
+
+    /// Removes the cached nodes that only the block with ordinal `block_ordinal`
+    /// contributed. Nodes shared with other blocks are kept.
+    pub fn forget_block(&mut self, block_ordinal: u64) {
+        if let Ok(leaf_index) = self.leaf_index(block_ordinal) {
+            self.tree.forget_leaf(leaf_index);
+        }
+    }
+
+    fn leaf_index(&self, block_ordinal: u64) -> Result<LeafIndex, BatchProofError> {
+        let out_of_range = || BatchProofError::BlockOrdinalOutOfRange {
+            block_ordinal,
+            num_blocks: self.num_blocks,
+        };
+        if block_ordinal == 0 || block_ordinal > self.num_blocks {
+            return Err(out_of_range());
+        }
+        // also rejects the blocks of a tree that does not fit a `usize`, see `tree_size`
+        match LeafIndex::try_from(block_ordinal - 1) {
+            Ok(leaf_index) if leaf_index < self.tree.tree_size => Ok(leaf_index),
+            _ => Err(out_of_range()),
+        }
+    }
+
+    fn commit_block(
+        &mut self,
+        block_ordinal: u64,
+        block_hash: CryptoHash,
+        proof: &MerklePath,
+        counts: &mut HashCounts,
+    ) -> Result<CryptoHash, BatchProofError> {
+        let leaf_index = self.leaf_index(block_ordinal)?;
+        self.tree.commit_leaf(leaf_index, block_hash, proof, counts)
+    }
+}
+
+/// Size of the tree of `num_blocks` blocks. A tree that does not fit a `usize`,
+/// e.g. more than `usize::MAX` blocks on wasm32, is left empty so that none of its
+/// blocks verify.
+fn tree_size(num_blocks: u64) -> usize {
+    LeafIndex::try_from(num_blocks).unwrap_or(0)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use near_primitives::{hash::CryptoHash as NearCryptoHash, merkle::PartialMerkleTree};
+
+    use super::*;
+    use crate::{test_utils::MockedHostFunctions, MerkleTree};
+
+    fn block_hashes(num_blocks: u64) -> Vec<CryptoHash> {
+        (0..num_blocks)
+            .map(|ordinal| CryptoHash::hash_borsh::<MockedHostFunctions, _>(&ordinal))
+            .collect()
+    }
+
+    fn block_tree(num_blocks: u64) -> MerkleTree<MockedHostFunctions> {
+        MerkleTree::from_leaf_hashes(block_hashes(num_blocks))
+    }
+
+    #[test]
+    fn test_layout_matches_near_block_merkle_tree() {
+        let mut partial_tree = PartialMerkleTree::default();
+        for (num_blocks, block_hash) in block_hashes(70).into_iter().enumerate() {
+            partial_tree.insert(NearCryptoHash(block_hash.0));
+            assert_eq!(
+                CryptoHash(partial_tree.root().0),
+                block_tree(num_blocks as u64 + 1).root(),
+                "{} blocks",
+                num_blocks + 1
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_blocks() {
+        let num_blocks = 11;
+        let (tree, hashes) = (block_tree(num_blocks), block_hashes(num_blocks));
+        let proofs = tree.paths();
+        let blocks = || {
+            (1..=num_blocks)
+                .zip(hashes.iter().copied())
+                .zip(proofs.iter())
+                .map(|((ordinal, hash), proof)| (ordinal, hash, proof))
+        };
+
+        let mut verifier = BlockMerkleVerifier::<MockedHostFunctions>::new(tree.root(), num_blocks);
+        let report = verifier.verify_batch(blocks());
+        assert!(report.is_valid());
+        // paths meet the nodes cached by the previous blocks
+        assert!(report.hashes_computed() < proofs.iter().map(Vec::len).sum());
+        // every block is known now
+        let report = verifier.verify_batch(blocks());
+        assert!(report.is_valid());
+        assert_eq!(report.hashes_computed(), 0);
+
+        let mut verifier = BlockMerkleVerifier::<MockedHostFunctions>::new(tree.root(), num_blocks);
+        assert_eq!(
+            verifier.verify(2, hashes[0], &proofs[0]),
+            Err(BatchProofError::InvalidPathShape {
+                leaf_index: 1,
+                tree_size: 11
+            })
+        );
+        assert!(matches!(
+            verifier.verify(1, hashes[1], &proofs[0]),
+            Err(BatchProofError::RootMismatch { .. })
+        ));
+        for block_ordinal in [0, 12] {
+            assert_eq!(
+                verifier.verify(block_ordinal, hashes[0], &proofs[0]),
+                Err(BatchProofError::BlockOrdinalOutOfRange {
+                    block_ordinal,
+                    num_blocks
+                })
+            );
+        }
+        assert_eq!(verifier.cache_len(), 0);
+
+        // the sibling proven along with a block is enough to check the next one
+        verifier.verify(5, hashes[4], &proofs[4]).unwrap();
+        assert!(matches!(
+            verifier.verify(6, hashes[4], &proofs[5]),
+            Err(BatchProofError::CacheMismatch { .. })
+        ));
+        let report = verifier.verify_batch([(6, hashes[5], &proofs[5])]);
+        assert_eq!(report.hashes_computed(), 0);
+
+        verifier.forget_block(5);
+        verifier.forget_block(6);
+        assert_eq!(verifier.cache_len(), 0);
+
+        // a tree too large for the target has no block to verify, whether its size
+        // fits a `usize` or not
+        let mut verifier = BlockMerkleVerifier::<MockedHostFunctions>::new(tree.root(), u64::MAX);
+        assert_eq!(verifier.num_blocks(), u64::MAX);
+        assert!(matches!(
+            verifier.verify(1, hashes[0], &proofs[0]),
+            Err(BatchProofError::TreeTooLarge { .. })
+                | Err(BatchProofError::BlockOrdinalOutOfRange { .. })
+        ));
+        verifier.set_head(tree.root(), num_blocks);
+        verifier.verify(1, hashes[0], &proofs[0]).unwrap();
+    }
+
+    #[test]
+    fn test_set_head_keeps_complete_subtrees() {
+        let (old, new) = (11, 21);
+        let (old_tree, new_tree) = (block_tree(old), block_tree(new));
+        let (hashes, old_paths) = (block_hashes(new), old_tree.paths());
+
+        let mut verifier = BlockMerkleVerifier::<MockedHostFunctions>::new(old_tree.root(), old);
+        verifier
+            .verify_all((1..=old).map(|ordinal| {
+                let leaf_index = ordinal as usize - 1;
+                (ordinal, hashes[leaf_index], &old_paths[leaf_index])
+            }))
+            .unwrap();
+
+        verifier.set_head(new_tree.root(), new);
+        assert_eq!(verifier.num_blocks(), new);
+        // the first 8 blocks, the next 2 and the 11th are full subtrees
+        assert_eq!(verifier.cache_len(), 8 + 4 + 2 + 1 + 2 + 1 + 1);
+
+        let new_paths = new_tree.paths();
+        for ordinal in 1..=new {
+            let leaf_index = ordinal as usize - 1;
+            verifier
+                .verify(ordinal, hashes[leaf_index], &new_paths[leaf_index])
+                .unwrap();
+        }
+        // blocks of the old head are proven without hashing
+        let report = verifier.verify_batch([(3, hashes[2], &new_paths[2])]);
+        assert_eq!(report.hashes_computed(), 0);
+        assert!(matches!(
+            verifier.verify(3, hashes[2], &old_paths[2]),
+            Err(BatchProofError::InvalidPathShape { .. })
+                | Err(BatchProofError::RootMismatch { .. })
+        ));
+    }
+}
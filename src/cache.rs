@@ -0,0 +1,549 @@
+//! Cache of the nodes that were already verified.
+
+use core::{cmp::Reverse, mem::size_of};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    vec::Vec,
+};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::{
+    BatchProofError, ComputedNodes, CryptoHash, HostFunctions, Index, Level, NodeCoordinates,
+};
+
+/// Upper bound on the size of the cache of a [`crate::ProofBatchVerifier`].
+///
+/// Once the bound is reached, the least recently used nodes are evicted, starting
+/// with the deepest levels: nodes close to the root are shared by the most proofs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum CacheCapacity {
+    Unbounded,
+    /// Maximum number of cached nodes.
+    Entries(usize),
+    /// Approximate maximum number of bytes used by the cached nodes, including the
+    /// record of the leaves that went through them.
+    Bytes(usize),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct CachedNodes {
+    pub(crate) inner: HashMap<(Level, Index), CryptoHash>,
+    /// nodes each leaf went through, either on its path or as one of its siblings,
+    /// keyed by the position of the leaf: leaves of unbalanced trees sit on
+    /// different levels, so their index alone does not tell them apart
+    pub(crate) path_item_cache_mapping: HashMap<(Level, Index), Vec<(Level, Index)>>,
+    /// leaves going through each node of `path_item_cache_mapping`, so that evicting
+    /// a node also drops it from the mapping
+    node_leaves: HashMap<(Level, Index), Vec<(Level, Index)>>,
+    /// number of (leaf, node) pairs in `path_item_cache_mapping`
+    references: usize,
+    /// hashes proven next to the cached nodes of a path, keyed by their position, so
+    /// that a snapshot can check every node against its parent
+    pub(crate) proven_siblings: HashMap<(Level, Index), CryptoHash>,
+    /// changes made since the last checkpoint, if one was taken
+    journal: Option<Journal>,
+    /// bound the cache was created with
+    capacity: CacheCapacity,
+    /// only tracked when the cache is bounded
+    eviction_queue: EvictionQueue,
+}
+
+/// Nodes met while walking a path up to the root, split between the ones the cache
+/// already held and the ones that were hashed, ready to be committed.
+#[derive(Debug, Default)]
+pub(crate) struct PathWalk {
+    /// whether the last node of the walk was found in the cache
+    cache_hit: bool,
+    /// nodes that were not cached yet
+    pub(crate) computed_nodes: ComputedNodes,
+    /// cached nodes the walk went through
+    pub(crate) used_nodes: Vec<(Level, Index)>,
+}
+
+/// Orders the cached nodes by eviction priority: deepest level first, then least
+/// recently used.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct EvictionQueue {
+    tick: u64,
+    last_used: HashMap<(Level, Index), u64>,
+    queue: BTreeSet<(Reverse<Level>, u64, Index)>,
+}
+
+impl EvictionQueue {
+    fn touch(&mut self, level: Level, index: Index) {
+        self.tick += 1;
+        if let Some(last_used) = self.last_used.insert((level, index), self.tick) {
+            self.queue.remove(&(Reverse(level), last_used, index));
+        }
+        self.queue.insert((Reverse(level), self.tick, index));
+    }
+
+    fn remove(&mut self, level: Level, index: Index) {
+        if let Some(last_used) = self.last_used.remove(&(level, index)) {
+            self.queue.remove(&(Reverse(level), last_used, index));
+        }
+    }
+
+    fn pop(&mut self) -> Option<(Level, Index)> {
+        let (Reverse(level), _, index) = self.queue.pop_first()?;
+        self.last_used.remove(&(level, index));
+        Some((level, index))
+    }
+}
+
+/// Serialized form of [`CachedNodes`], ordered so that equal caches give equal bytes.
+#[derive(Debug, BorshSerialize, BorshDeserialize)]
+pub(crate) struct CacheSnapshot {
+    capacity: CacheCapacity,
+    nodes: Vec<((Level, Index), CryptoHash)>,
+    proven_siblings: Vec<((Level, Index), CryptoHash)>,
+    leaves: Vec<LeafNodes>,
+}
+
+/// Position of a leaf, along with the nodes it went through.
+type LeafNodes = ((Level, Index), Vec<(Level, Index)>);
+
+/// Records what was added to [`CachedNodes`] since a checkpoint, so it can be undone.
+///
+/// Entries are dropped as soon as the node they refer to leaves the cache, so the
+/// journal never outgrows the cache itself.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Journal {
+    inserted_nodes: HashSet<(Level, Index)>,
+    /// (leaf, node) pairs added to the mapping
+    references: HashSet<((Level, Index), (Level, Index))>,
+}
+
+impl CachedNodes {
+    pub(crate) fn capacity(&self) -> CacheCapacity {
+        self.capacity
+    }
+
+    pub(crate) fn with_capacity(capacity: CacheCapacity) -> Self {
+        Self {
+            inner: HashMap::new(),
+            path_item_cache_mapping: HashMap::new(),
+            node_leaves: HashMap::new(),
+            references: 0,
+            proven_siblings: HashMap::new(),
+            journal: None,
+            capacity,
+            eviction_queue: EvictionQueue::default(),
+        }
+    }
+
+    /// Approximate number of bytes used by a node, including the eviction bookkeeping.
+    pub(crate) fn bounded_entry_size() -> usize {
+        size_of::<((Level, Index), CryptoHash)>()
+            + size_of::<((Level, Index), u64)>()
+            + size_of::<(Reverse<Level>, u64, Index)>()
+    }
+
+    /// Approximate number of bytes used to record that a leaf went through a node,
+    /// in both directions.
+    pub(crate) fn reference_size() -> usize {
+        2 * size_of::<(Level, Index)>()
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.capacity != CacheCapacity::Unbounded
+    }
+
+    fn over_capacity(&self) -> bool {
+        match self.capacity {
+            CacheCapacity::Unbounded => false,
+            CacheCapacity::Entries(entries) => self.len() > entries,
+            CacheCapacity::Bytes(bytes) => self.size_bytes() > bytes,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Approximate number of bytes used by the cached nodes, including the record of
+    /// the leaves that went through them.
+    pub(crate) fn size_bytes(&self) -> usize {
+        let entry_size = match self.is_bounded() {
+            false => size_of::<((Level, Index), CryptoHash)>(),
+            true => Self::bounded_entry_size(),
+        };
+        self.len() * entry_size
+            + self.proven_siblings.len() * size_of::<((Level, Index), CryptoHash)>()
+            + self.references * Self::reference_size()
+    }
+
+    pub(crate) fn insert(&mut self, level: Level, index: Index, hash: CryptoHash) -> bool {
+        if self.inner.contains_key(&(level, index)) {
+            return false;
+        }
+        self.inner.insert((level, index), hash);
+        if let Some(journal) = self.journal.as_mut() {
+            journal.inserted_nodes.insert((level, index));
+        }
+        if self.is_bounded() {
+            self.eviction_queue.touch(level, index);
+            self.evict();
+        }
+        true
+    }
+
+    /// Evicts nodes until the cache fits in its capacity.
+    fn evict(&mut self) {
+        while self.over_capacity() {
+            match self.eviction_queue.pop() {
+                Some((level, index)) => self.remove(level, index),
+                None => break,
+            }
+        }
+    }
+
+    /// Returns whether the node at the given coordinates is already cached, failing
+    /// if it is cached with another hash.
+    pub(crate) fn check(
+        &self,
+        level: Level,
+        index: Index,
+        hash: CryptoHash,
+    ) -> Result<bool, BatchProofError> {
+        match self.inner.get(&(level, index)) {
+            None => Ok(false),
+            // ensure that, if the value was cached it matches the calculation made above
+            // this is important, otherwise when most of the intermediates nodes are cached, if this check
+            // is not made, a wrong proof could be passed and stil "yield" the right root hash
+            Some(cached) if cached != &hash => Err(BatchProofError::CacheMismatch {
+                level,
+                index,
+                cached: *cached,
+                computed: hash,
+            }),
+            Some(_) => Ok(true),
+        }
+    }
+
+    /// Hash of `node` if `walk` can take it from the cache instead of hashing it.
+    pub(crate) fn reuse(&self, walk: &mut PathWalk, node: (Level, Index)) -> Option<CryptoHash> {
+        // the child was already known, so the cached parent can be trusted as is
+        if !walk.cache_hit {
+            return None;
+        }
+        let hash = *self.inner.get(&node)?;
+        walk.used_nodes.push(node);
+        Some(hash)
+    }
+
+    /// Records the hash `walk` computed for `node`, failing if the cache holds
+    /// another one.
+    pub(crate) fn record(
+        &self,
+        walk: &mut PathWalk,
+        node: (Level, Index),
+        hash: CryptoHash,
+    ) -> Result<(), BatchProofError> {
+        walk.cache_hit = self.check(node.0, node.1, hash)?;
+        if walk.cache_hit {
+            walk.used_nodes.push(node);
+        } else {
+            walk.computed_nodes.push((node, hash));
+        }
+        Ok(())
+    }
+
+    /// Moves `walk` up to `node`, taking it from the cache when possible and
+    /// hashing it with `compute` otherwise. Returns the hash of the node, and whether
+    /// it came from the cache.
+    pub(crate) fn step(
+        &self,
+        walk: &mut PathWalk,
+        node: (Level, Index),
+        compute: impl FnOnce() -> CryptoHash,
+    ) -> Result<(CryptoHash, bool), BatchProofError> {
+        if let Some(hash) = self.reuse(walk, node) {
+            return Ok((hash, true));
+        }
+        let hash = compute();
+        self.record(walk, node, hash)?;
+        Ok((hash, false))
+    }
+
+    /// Removes the node, along with every record of the leaves that went through it.
+    fn remove(&mut self, level: Level, index: Index) {
+        self.inner.remove(&(level, index));
+        self.eviction_queue.remove(level, index);
+        if level > 0 {
+            self.proven_siblings.remove(&(level, index ^ 1));
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            journal.inserted_nodes.remove(&(level, index));
+        }
+        self.node_leaves
+            .remove(&(level, index))
+            .unwrap_or_default()
+            .into_iter()
+            .for_each(|leaf| {
+                self.unlink(leaf, (level, index));
+            });
+    }
+
+    /// Marks the given nodes as used, so they are evicted last.
+    pub(crate) fn touch(&mut self, used_nodes: &[(Level, Index)]) {
+        if !self.is_bounded() {
+            return;
+        }
+        used_nodes.iter().for_each(|(level, index)| {
+            if self.inner.contains_key(&(*level, *index)) {
+                self.eviction_queue.touch(*level, *index);
+            }
+        });
+    }
+
+    /// Merges the nodes of a verified path into the cache, on behalf of the leaf at
+    /// position `leaf`.
+    pub(crate) fn commit(
+        &mut self,
+        computed_nodes: ComputedNodes,
+        used_nodes: &[(Level, Index)],
+        leaf: (Level, Index),
+    ) {
+        self.touch(used_nodes);
+        let path_nodes = computed_nodes
+            .into_iter()
+            .map(|((level, index), hash)| {
+                self.insert(level, index, hash);
+                (level, index)
+            })
+            .chain(used_nodes.iter().copied())
+            .collect::<Vec<_>>();
+        self.reference(leaf, &path_nodes);
+    }
+
+    /// Keeps the hashes a verified path proved next to the nodes it cached, unless
+    /// they are cached themselves.
+    pub(crate) fn prove_siblings(&mut self, sibling_nodes: &[NodeCoordinates]) {
+        sibling_nodes.iter().for_each(|node| {
+            let (level, index) = (node.level, node.index);
+            match node.hash {
+                Some(hash)
+                    if self.inner.contains_key(&(level, index ^ 1))
+                        && !self.inner.contains_key(&(level, index)) =>
+                {
+                    self.proven_siblings.insert((level, index), hash);
+                }
+                _ => {}
+            }
+        });
+    }
+
+    /// Records that the leaf at position `leaf` went through the given nodes.
+    /// Nodes that are not cached, e.g. because they were already evicted, are skipped.
+    fn reference(&mut self, leaf: (Level, Index), nodes: &[(Level, Index)]) {
+        for node in nodes {
+            if !self.inner.contains_key(node) {
+                continue;
+            }
+            let leaf_nodes = self.path_item_cache_mapping.entry(leaf).or_default();
+            if leaf_nodes.contains(node) {
+                continue;
+            }
+            leaf_nodes.push(*node);
+            self.node_leaves.entry(*node).or_default().push(leaf);
+            self.references += 1;
+            if let Some(journal) = self.journal.as_mut() {
+                journal.references.insert((leaf, *node));
+            }
+        }
+        self.evict();
+    }
+
+    /// Drops `node` from the nodes of `leaf`, returning whether it was there. The
+    /// reverse record in `node_leaves` is left to the caller.
+    fn unlink(&mut self, leaf: (Level, Index), node: (Level, Index)) -> bool {
+        let leaf_nodes = match self.path_item_cache_mapping.get_mut(&leaf) {
+            Some(leaf_nodes) => leaf_nodes,
+            None => return false,
+        };
+        let len = leaf_nodes.len();
+        leaf_nodes.retain(|leaf_node| *leaf_node != node);
+        if leaf_nodes.len() == len {
+            return false;
+        }
+        if leaf_nodes.is_empty() {
+            self.path_item_cache_mapping.remove(&leaf);
+        }
+        self.references -= 1;
+        if let Some(journal) = self.journal.as_mut() {
+            journal.references.remove(&(leaf, node));
+        }
+        true
+    }
+
+    /// Drops the reference of `leaf` to `node`, removing the node once no leaf goes
+    /// through it anymore.
+    fn release(&mut self, leaf: (Level, Index), node: (Level, Index)) {
+        if !self.unlink(leaf, node) {
+            return;
+        }
+        if let Some(leaves) = self.node_leaves.get_mut(&node) {
+            leaves.retain(|node_leaf| *node_leaf != leaf);
+            if leaves.is_empty() {
+                self.node_leaves.remove(&node);
+                self.remove(node.0, node.1);
+            }
+        }
+    }
+
+    /// Removes the nodes that only the leaf at position `leaf` went through.
+    ///
+    /// Forgetting is final, a rollback does not bring the leaf back.
+    pub(crate) fn forget_leaf(&mut self, leaf: (Level, Index)) {
+        if let Some(nodes) = self.path_item_cache_mapping.get(&leaf).cloned() {
+            nodes.into_iter().for_each(|node| self.release(leaf, node));
+        }
+    }
+
+    pub(crate) fn checkpoint(&mut self) {
+        self.journal = Some(Journal::default());
+    }
+
+    pub(crate) fn rollback(&mut self) {
+        let journal = match self.journal.replace(Journal::default()) {
+            Some(journal) => journal,
+            None => return,
+        };
+        journal
+            .references
+            .into_iter()
+            .for_each(|(leaf, node)| self.release(leaf, node));
+        journal
+            .inserted_nodes
+            .into_iter()
+            .for_each(|(level, index)| self.remove(level, index));
+    }
+
+    pub(crate) fn extend_from_given(
+        &mut self,
+        given_nodes: &[NodeCoordinates],
+        leaf: (Level, Index),
+    ) -> Result<(), BatchProofError> {
+        // make sure every node carries a hash before touching the cache
+        if given_nodes.iter().any(|node| node.hash.is_none()) {
+            return Err(BatchProofError::MalformedProof);
+        }
+
+        let given = given_nodes
+            .iter()
+            .map(|NodeCoordinates { index, level, hash }| {
+                self.insert(*level, *index, hash.unwrap());
+                (*level, *index)
+            })
+            .collect::<Vec<_>>();
+        self.reference(leaf, &given);
+        Ok(())
+    }
+
+    pub(crate) fn snapshot(&self) -> CacheSnapshot {
+        let mut nodes = self
+            .inner
+            .iter()
+            .map(|(key, hash)| (*key, *hash))
+            .collect::<Vec<_>>();
+        nodes.sort();
+        let mut proven_siblings = self
+            .proven_siblings
+            .iter()
+            .map(|(key, hash)| (*key, *hash))
+            .collect::<Vec<_>>();
+        proven_siblings.sort();
+        let mut leaves = self
+            .path_item_cache_mapping
+            .iter()
+            .map(|(leaf, nodes)| (*leaf, nodes.clone()))
+            .collect::<Vec<_>>();
+        leaves.sort();
+        CacheSnapshot {
+            capacity: self.capacity(),
+            nodes,
+            proven_siblings,
+            leaves,
+        }
+    }
+
+    /// Rebuilds the cache out of a snapshot, only keeping the nodes that hash up to
+    /// `trusted_root` along with their sibling, either cached or proven next to them.
+    ///
+    /// Anything else cannot be told apart from a tampered node, so it is dropped.
+    /// Fails if the snapshot holds a root other than `trusted_root`.
+    pub(crate) fn restore<HF: HostFunctions>(
+        snapshot: CacheSnapshot,
+        trusted_root: Option<CryptoHash>,
+    ) -> Result<Self, BatchProofError> {
+        let mut cache = Self::with_capacity(snapshot.capacity);
+        let trusted_root = match trusted_root {
+            Some(trusted_root) => trusted_root,
+            // nothing to check the nodes against
+            None => return Ok(cache),
+        };
+
+        // ordered by level, so parents are always validated before their children
+        let nodes = snapshot.nodes.into_iter().collect::<BTreeMap<_, _>>();
+        let proven_siblings = snapshot
+            .proven_siblings
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let mut valid = HashSet::new();
+        for (&(level, index), &hash) in nodes.iter() {
+            if level == 0 {
+                if (level, index) == (0, 0) {
+                    if hash != trusted_root {
+                        return Err(BatchProofError::RootMismatch {
+                            expected: trusted_root,
+                            computed: hash,
+                        });
+                    }
+                    valid.insert((level, index));
+                }
+                continue;
+            }
+            let parent = (level - 1, index / 2);
+            let sibling = (level, index ^ 1);
+            let (sibling_hash, parent_hash) = match (
+                proven_siblings
+                    .get(&sibling)
+                    .or_else(|| nodes.get(&sibling)),
+                nodes.get(&parent),
+            ) {
+                (Some(sibling_hash), Some(parent_hash)) if valid.contains(&parent) => {
+                    (sibling_hash, parent_hash)
+                }
+                _ => continue,
+            };
+            let computed = match index % 2 {
+                0 => CryptoHash::hash_pair::<HF>(&hash, sibling_hash),
+                _ => CryptoHash::hash_pair::<HF>(sibling_hash, &hash),
+            };
+            if computed == *parent_hash {
+                valid.insert((level, index));
+            }
+        }
+
+        nodes
+            .into_iter()
+            .filter(|(key, _)| valid.contains(key))
+            .for_each(|((level, index), hash)| {
+                cache.insert(level, index, hash);
+            });
+        // only the siblings of the nodes that were kept, as those checked out
+        proven_siblings
+            .into_iter()
+            .filter(|((level, index), _)| cache.inner.contains_key(&(*level, index ^ 1)))
+            .for_each(|(key, hash)| {
+                cache.proven_siblings.insert(key, hash);
+            });
+        snapshot
+            .leaves
+            .into_iter()
+            .for_each(|(leaf, nodes)| cache.reference(leaf, &nodes));
+        Ok(cache)
+    }
+}
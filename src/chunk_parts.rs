@@ -0,0 +1,220 @@
+//! Verification of the erasure-coded parts of a NEAR chunk.
+//!
+//! A chunk is split into a known number of parts, and the chunk header commits to
+//! all of them through `encoded_merkle_root`, the root of the `merklize` tree of the
+//! parts in order. Every part carries its path to that root, and its ordinal is its
+//! leaf index, so [`ChunkPartsVerifier`] checks that each path is exactly the one of
+//! its ordinal, and caches the nodes and siblings the parts of a chunk share.
+
+use crate::{
+    positioned::PositionedTree, BatchProofError, BatchReport, CacheCapacity, CryptoHash,
+    HashCounts, HostFunctions, LeafIndex, MerklePath, ProofReport,
+};
+
+/// Verifies the parts of a single chunk against its `encoded_merkle_root`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChunkPartsVerifier<HF: HostFunctions> {
+    tree: PositionedTree<HF>,
+}
+
+impl<HF: HostFunctions> ChunkPartsVerifier<HF> {
+    /// Creates a verifier for the parts of a chunk split in `total_parts` parts.
+    ///
+    /// A chunk cannot be split in more parts than a tree can hold, so with more
+    /// than `usize::MAX / 2 + 1` parts every part is rejected with
+    /// [`BatchProofError::TreeTooLarge`].
+    pub fn new(encoded_merkle_root: CryptoHash, total_parts: usize) -> Self {
+        Self::with_capacity(encoded_merkle_root, total_parts, CacheCapacity::Unbounded)
+    }
+
+    /// Same as [`Self::new`], with a cache that never grows past `capacity`.
+    pub fn with_capacity(
+        encoded_merkle_root: CryptoHash,
+        total_parts: usize,
+        capacity: CacheCapacity,
+    ) -> Self {
+        Self {
+            tree: PositionedTree::with_capacity(encoded_merkle_root, total_parts, capacity),
+        }
+    }
+
+    /// Root the parts are verified against.
+    pub fn encoded_merkle_root(&self) -> CryptoHash {
+        self.tree.root
+    }
+
+    /// Number of parts of the chunk.
+    pub fn total_parts(&self) -> usize {
+        self.tree.tree_size
+    }
+
+    /// Number of nodes currently cached.
+    pub fn cache_len(&self) -> usize {
+        self.tree.cached_nodes.len()
+    }
+
+    /// Leaf of a part in the tree: the hash of its borsh serialization, as
+    /// `merklize` does.
+    pub fn part_hash(part: &[u8]) -> CryptoHash {
+        CryptoHash::hash_borsh::<HF, _>(&part)
+    }
+
+    /// Verifies that `part` is the part with ordinal `part_ord` of the chunk.
+    pub fn verify_part(
+        &mut self,
+        part_ord: u64,
+        part: &[u8],
+        proof: &MerklePath,
+    ) -> Result<(), BatchProofError> {
+        self.verify_part_hash(part_ord, Self::part_hash(part), proof)
+    }
+
+    /// Same as [`Self::verify_part`], for a part that was already hashed with
+    /// [`Self::part_hash`].
+    pub fn verify_part_hash(
+        &mut self,
+        part_ord: u64,
+        part_hash: CryptoHash,
+        proof: &MerklePath,
+    ) -> Result<(), BatchProofError> {
+        self.commit_part(part_ord, part_hash, proof, &mut HashCounts::default())
+            .map(|_| ())
+    }
+
+    /// Verifies every `(part_ord, part, proof)` of a batch, without stopping at the
+    /// first failure.
+    pub fn verify_parts<'a>(
+        &mut self,
+        parts: impl IntoIterator<Item = (u64, &'a [u8], &'a MerklePath)>,
+    ) -> BatchReport {
+        let proofs = parts
+            .into_iter()
+            .map(|(part_ord, part, proof)| {
+                let mut counts = HashCounts::default();
+                let result = self.commit_part(part_ord, Self::part_hash(part), proof, &mut counts);
+                ProofReport::new(result, counts.computed, counts.cached)
+            })
+            .collect();
+        BatchReport { proofs }
+    }
+
+    fn commit_part(
+        &mut self,
+        part_ord: u64,
+        part_hash: CryptoHash,
+        proof: &MerklePath,
+        counts: &mut HashCounts,
+    ) -> Result<CryptoHash, BatchProofError> {
+        // also rejects ordinals that do not fit a leaf index
+        let leaf_index = LeafIndex::try_from(part_ord).unwrap_or(LeafIndex::MAX);
+        self.tree.commit_leaf(leaf_index, part_hash, proof, counts)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{merklize, MockedHostFunctions};
+
+    type Verifier = ChunkPartsVerifier<MockedHostFunctions>;
+
+    /// Parts of a chunk, as boxed slices like in `near_primitives`.
+    fn parts(total_parts: usize) -> Vec<Box<[u8]>> {
+        (0..total_parts)
+            .map(|part_ord| vec![part_ord as u8; 10 + part_ord].into_boxed_slice())
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_chunk_parts() {
+        let total_parts = 13;
+        let parts = parts(total_parts);
+        let (root, proofs) = merklize(&parts);
+        let batch = || {
+            parts
+                .iter()
+                .zip(proofs.iter())
+                .enumerate()
+                .map(|(part_ord, (part, proof))| (part_ord as u64, &part[..], proof))
+        };
+
+        let mut verifier = Verifier::new(root, total_parts);
+        let report = verifier.verify_parts(batch());
+        assert!(report.is_valid());
+        // every inner node is hashed once, siblings of verified parts are cached
+        assert_eq!(report.hashes_computed(), total_parts - 1);
+        assert_eq!(verifier.verify_parts(batch()).hashes_computed(), 0);
+    }
+
+    #[test]
+    fn test_reject_misplaced_parts() {
+        let total_parts = 13;
+        let parts = parts(total_parts);
+        let (root, proofs) = merklize(&parts);
+
+        let mut verifier = Verifier::new(root, total_parts);
+        // the right path, claimed for another part
+        assert_eq!(
+            verifier.verify_part(3, &parts[2], &proofs[2]),
+            Err(BatchProofError::InvalidPathShape {
+                leaf_index: 3,
+                tree_size: total_parts
+            })
+        );
+        assert!(matches!(
+            verifier.verify_part(2, &parts[3], &proofs[2]),
+            Err(BatchProofError::RootMismatch { .. })
+        ));
+        assert!(matches!(
+            verifier.verify_part(13, &parts[12], &proofs[12]),
+            Err(BatchProofError::LeafIndexOutOfBounds { .. })
+        ));
+        assert!(matches!(
+            verifier.verify_part(u64::MAX, &parts[12], &proofs[12]),
+            Err(BatchProofError::LeafIndexOutOfBounds { .. })
+        ));
+        assert_eq!(verifier.cache_len(), 0);
+
+        // once a part is verified, its sibling is known
+        verifier.verify_part(2, &parts[2], &proofs[2]).unwrap();
+        assert!(matches!(
+            verifier.verify_part(3, &parts[2], &proofs[3]),
+            Err(BatchProofError::CacheMismatch { .. })
+        ));
+        verifier.verify_part(3, &parts[3], &proofs[3]).unwrap();
+
+        // a chunk with another number of parts has paths of another shape
+        let (other_root, other_proofs) = merklize(&parts[..12]);
+        let mut verifier = Verifier::new(other_root, total_parts);
+        assert!(matches!(
+            verifier.verify_part(8, &parts[8], &other_proofs[8]),
+            Err(BatchProofError::InvalidPathShape { .. })
+        ));
+    }
+
+    #[test]
+    fn test_reject_too_many_parts() {
+        let parts = parts(4);
+        let (root, proofs) = merklize(&parts);
+
+        for total_parts in [(usize::MAX >> 1) + 2, usize::MAX] {
+            let mut verifier = Verifier::new(root, total_parts);
+            assert_eq!(
+                verifier.verify_part_hash(0, Verifier::part_hash(&parts[0]), &proofs[0]),
+                Err(BatchProofError::TreeTooLarge {
+                    tree_size: total_parts
+                })
+            );
+            let report = verifier.verify_parts(
+                parts
+                    .iter()
+                    .zip(proofs.iter())
+                    .enumerate()
+                    .map(|(part_ord, (part, proof))| (part_ord as u64, &part[..], proof)),
+            );
+            assert_eq!(report.proofs.len(), 4);
+            assert!(!report.is_valid());
+            assert_eq!(verifier.cache_len(), 0);
+        }
+    }
+}
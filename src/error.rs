@@ -0,0 +1,209 @@
+use core::fmt;
+
+use crate::{CryptoHash, Index, LeafIndex, Level};
+
+/// Errors that can be raised while verifying merkle proofs in a batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchProofError {
+    /// A node computed from the proof does not match the value already cached
+    /// at the same coordinates.
+    CacheMismatch {
+        level: Level,
+        index: Index,
+        cached: CryptoHash,
+        computed: CryptoHash,
+    },
+    /// The root computed from the proof is not the expected one.
+    RootMismatch {
+        expected: CryptoHash,
+        computed: CryptoHash,
+    },
+    /// The verifier is pinned to a different root than the one given.
+    UnexpectedRoot {
+        pinned: CryptoHash,
+        given: CryptoHash,
+    },
+    /// Two proofs given together disagree on the hash of a node, so they do not
+    /// belong to the same tree.
+    ConflictingProofs { level: Level, index: Index },
+    /// The leaf index does not exist in a tree of the given size.
+    LeafIndexOutOfBounds {
+        leaf_index: LeafIndex,
+        tree_size: usize,
+    },
+    /// The tree has too many leaves for the positions of its nodes to be computed.
+    TreeTooLarge { tree_size: usize },
+    /// The merkle path cannot be the path of the leaf in a tree of the given size,
+    /// either because of its length or because its directions lead to another leaf.
+    InvalidPathShape {
+        leaf_index: LeafIndex,
+        tree_size: usize,
+    },
+    /// The merkle path does not contain any item, which is only valid for a tree
+    /// with a single leaf.
+    EmptyProof,
+    /// The merkle path cannot be mapped onto the tree coordinates.
+    MalformedProof,
+    /// The snapshot cannot be decoded, or was written by an unsupported version.
+    InvalidSnapshot,
+    /// [`crate::HostFunctions::sha256_many`] did not return one hash per input.
+    HostHashCountMismatch { inputs: usize, hashes: usize },
+    /// The block is not committed to by a block merkle root over `num_blocks` blocks.
+    BlockOrdinalOutOfRange { block_ordinal: u64, num_blocks: u64 },
+    /// A trie node needed to look a key up is neither cached nor part of the proof.
+    MissingTrieNode { hash: CryptoHash },
+    /// A trie node of the proof hashes to the expected value but cannot be decoded.
+    MalformedTrieNode { hash: CryptoHash },
+    /// The key holds another value than the expected one, `None` meaning no value.
+    StateValueMismatch {
+        expected: Option<CryptoHash>,
+        found: Option<CryptoHash>,
+    },
+    /// The header is not above the height of the head.
+    StaleHeader { height: u64, head_height: u64 },
+    /// The header is so high that the height its approvals sign does not fit a `u64`.
+    HeightOutOfRange { height: u64 },
+    /// The header is neither in the epoch of the head nor in the next one, or the
+    /// block producers of its epoch are not known yet.
+    UnknownEpoch { epoch_id: CryptoHash },
+    /// The header moves to the next epoch without the block producers of the
+    /// epoch after it.
+    MissingNextBlockProducers { epoch_id: CryptoHash },
+    /// The block producers of the next epoch do not hash to `next_bp_hash`.
+    NextBlockProducersMismatch {
+        expected: CryptoHash,
+        computed: CryptoHash,
+    },
+    /// The approval of the block producer at `index` is not a valid signature.
+    InvalidApproval { index: usize },
+    /// The approvals do not hold more than two thirds of the stake of the epoch.
+    InsufficientApprovals { approved: u128, total: u128 },
+}
+
+impl fmt::Display for BatchProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchProofError::CacheMismatch {
+                level,
+                index,
+                cached,
+                computed,
+            } => write!(
+                f,
+                "cached node at level {} index {} is {} but {} was computed",
+                level, index, cached, computed
+            ),
+            BatchProofError::RootMismatch { expected, computed } => {
+                write!(
+                    f,
+                    "expected root {} but {} was computed",
+                    expected, computed
+                )
+            }
+            BatchProofError::UnexpectedRoot { pinned, given } => write!(
+                f,
+                "verifier is pinned to root {} but {} was given",
+                pinned, given
+            ),
+            BatchProofError::ConflictingProofs { level, index } => write!(
+                f,
+                "proofs disagree on the node at level {} index {}",
+                level, index
+            ),
+            BatchProofError::LeafIndexOutOfBounds {
+                leaf_index,
+                tree_size,
+            } => write!(
+                f,
+                "leaf {} does not exist in a tree of {} leaves",
+                leaf_index, tree_size
+            ),
+            BatchProofError::TreeTooLarge { tree_size } => {
+                write!(f, "a tree of {} leaves is too large", tree_size)
+            }
+            BatchProofError::InvalidPathShape {
+                leaf_index,
+                tree_size,
+            } => write!(
+                f,
+                "merkle path does not fit leaf {} of a tree of {} leaves",
+                leaf_index, tree_size
+            ),
+            BatchProofError::EmptyProof => write!(f, "merkle proof is empty"),
+            BatchProofError::MalformedProof => write!(f, "merkle proof is malformed"),
+            BatchProofError::InvalidSnapshot => write!(f, "verifier snapshot is invalid"),
+            BatchProofError::HostHashCountMismatch { inputs, hashes } => write!(
+                f,
+                "host functions returned {} hashes for {} inputs",
+                hashes, inputs
+            ),
+            BatchProofError::BlockOrdinalOutOfRange {
+                block_ordinal,
+                num_blocks,
+            } => write!(
+                f,
+                "block {} is not one of the {} blocks of the block merkle tree",
+                block_ordinal, num_blocks
+            ),
+            BatchProofError::MissingTrieNode { hash } => {
+                write!(f, "trie node {} is missing from the proof", hash)
+            }
+            BatchProofError::MalformedTrieNode { hash } => {
+                write!(f, "trie node {} cannot be decoded", hash)
+            }
+            BatchProofError::StateValueMismatch { expected, found } => write!(
+                f,
+                "expected value {} but the state holds {}",
+                ValueHash(expected),
+                ValueHash(found)
+            ),
+            BatchProofError::StaleHeader {
+                height,
+                head_height,
+            } => write!(
+                f,
+                "header at height {} does not extend the head at height {}",
+                height, head_height
+            ),
+            BatchProofError::HeightOutOfRange { height } => {
+                write!(f, "header at height {} is too high to be approved", height)
+            }
+            BatchProofError::UnknownEpoch { epoch_id } => {
+                write!(f, "block producers of epoch {} are unknown", epoch_id)
+            }
+            BatchProofError::MissingNextBlockProducers { epoch_id } => write!(
+                f,
+                "header moving to epoch {} lacks the next block producers",
+                epoch_id
+            ),
+            BatchProofError::NextBlockProducersMismatch { expected, computed } => write!(
+                f,
+                "expected next block producers hash {} but {} was computed",
+                expected, computed
+            ),
+            BatchProofError::InvalidApproval { index } => {
+                write!(f, "approval of block producer {} is invalid", index)
+            }
+            BatchProofError::InsufficientApprovals { approved, total } => write!(
+                f,
+                "approvals hold {} of a total stake of {}, more than two thirds is needed",
+                approved, total
+            ),
+        }
+    }
+}
+
+/// Displays the hash of an optional state value.
+struct ValueHash<'a>(&'a Option<CryptoHash>);
+
+impl fmt::Display for ValueHash<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(hash) => write!(f, "{}", hash),
+            None => write!(f, "no value"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BatchProofError {}
@@ -1,3 +1,137 @@
+//! Hashing and signature primitives the verifiers rely on, provided by the
+//! environment they run in.
+
+use std::vec::Vec;
+
+/// Functions provided by the host the verifier runs on.
+///
+/// Every hash computed by [`crate::ProofBatchVerifier`] goes through this trait,
+/// so that on-chain deployments can use the (metered) host implementation.
 pub trait HostFunctions {
     fn sha256(data: &[u8]) -> [u8; 32];
+
+    /// Hashes the concatenation of two child nodes, which is also the borsh
+    /// serialization of the pair. Hosts can override it to skip the copy into a
+    /// single buffer.
+    fn sha256_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        let mut buffer = [0u8; 64];
+        buffer[..32].copy_from_slice(left);
+        buffer[32..].copy_from_slice(right);
+        Self::sha256(&buffer)
+    }
+
+    /// Hashes every input, e.g. with a multi-buffer implementation or a host call
+    /// taking many inputs at once. Used by [`crate::ProofBatchVerifier::verify_batch_by_level`],
+    /// which expects one hash per input, in order.
+    fn sha256_many(inputs: &[&[u8]]) -> Vec<[u8; 32]> {
+        inputs.iter().map(|input| Self::sha256(input)).collect()
+    }
+
+    /// Checks an ed25519 `signature` of `message` by `public_key`. Only needed to
+    /// verify light client headers with [`crate::LightClientHeaderVerifier`].
+    ///
+    /// Hosts without signature verification keep the default, which rejects every
+    /// signature, so that headers are never trusted without their approvals.
+    fn ed25519_verify(signature: &[u8; 64], message: &[u8], public_key: &[u8; 32]) -> bool {
+        let _ = (signature, message, public_key);
+        false
+    }
+}
+
+/// Pure Rust implementation backed by the `sha2` crate, and by `ed25519-dalek` for
+/// signatures when that feature is enabled.
+#[cfg(feature = "sha2")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sha2HostFunctions;
+
+#[cfg(feature = "sha2")]
+impl HostFunctions for Sha2HostFunctions {
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        use sha2::Digest;
+        sha2::Sha256::digest(data).into()
+    }
+
+    fn sha256_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        use sha2::Digest;
+        sha2::Sha256::new()
+            .chain_update(left)
+            .chain_update(right)
+            .finalize()
+            .into()
+    }
+
+    #[cfg(feature = "ed25519-dalek")]
+    fn ed25519_verify(signature: &[u8; 64], message: &[u8], public_key: &[u8; 32]) -> bool {
+        use ed25519_dalek::Verifier;
+        match (
+            ed25519_dalek::PublicKey::from_bytes(public_key),
+            ed25519_dalek::Signature::from_bytes(signature),
+        ) {
+            (Ok(public_key), Ok(signature)) => public_key.verify(message, &signature).is_ok(),
+            _ => false,
+        }
+    }
+}
+
+/// Implementation for NEAR contracts, backed by `near_sdk::env::sha256`.
+///
+/// `near-sdk` 4.1 has no host function for ed25519, so signatures are rejected.
+#[cfg(feature = "near-sdk")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NearHostFunctions;
+
+#[cfg(feature = "near-sdk")]
+impl HostFunctions for NearHostFunctions {
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        near_sdk::env::sha256(data)
+            .try_into()
+            .expect("sha256 digest is 32 bytes long")
+    }
+}
+
+/// Implementation for Substrate runtimes, backed by `sp_io::hashing::sha2_256` and
+/// `sp_io::crypto::ed25519_verify`.
+#[cfg(feature = "sp-io")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubstrateHostFunctions;
+
+#[cfg(feature = "sp-io")]
+impl HostFunctions for SubstrateHostFunctions {
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        sp_io::hashing::sha2_256(data)
+    }
+
+    fn ed25519_verify(signature: &[u8; 64], message: &[u8], public_key: &[u8; 32]) -> bool {
+        sp_io::crypto::ed25519_verify(&(*signature).into(), message, &(*public_key).into())
+    }
+}
+
+#[cfg(all(test, feature = "sha2"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockedHostFunctions;
+
+    #[test]
+    fn test_sha2_host_functions_match_mocked() {
+        let (left, right) = ([1u8; 32], [2u8; 32]);
+        let inputs: [&[u8]; 3] = [b"", b"batch-merkle-proofs", &[7u8; 200]];
+        for input in inputs {
+            assert_eq!(
+                Sha2HostFunctions::sha256(input),
+                MockedHostFunctions::sha256(input)
+            );
+        }
+        assert_eq!(
+            Sha2HostFunctions::sha256_pair(&left, &right),
+            MockedHostFunctions::sha256_pair(&left, &right)
+        );
+        assert_ne!(
+            Sha2HostFunctions::sha256_pair(&left, &right),
+            Sha2HostFunctions::sha256_pair(&right, &left)
+        );
+        assert_eq!(
+            Sha2HostFunctions::sha256_many(&inputs),
+            MockedHostFunctions::sha256_many(&inputs)
+        );
+    }
 }
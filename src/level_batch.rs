@@ -0,0 +1,329 @@
+//! Level by level verification of a batch of proofs.
+//!
+//! Instead of walking every proof up to the root one after the other, all the
+//! proofs of a batch are advanced one level at a time. The nodes that have to be
+//! hashed at a level are gathered across every proof and handed to
+//! [`HostFunctions::sha256_many`] at once, which lets hosts use multi-buffer SHA-256
+//! or precompiles taking many inputs.
+
+use std::{collections::HashMap, vec::Vec};
+
+use crate::{
+    cache::PathWalk, node_coordinates, sibling_nodes, BatchProofError, BatchReport, CryptoHash,
+    Direction, HostFunctions, Index, Level, MerklePath, NodeCoordinates, ProofBatchVerifier,
+    ProofReport,
+};
+
+/// Progress of a single proof through the levels of the tree.
+struct PendingProof<'a> {
+    proof: &'a MerklePath,
+    /// nodes to calculate, from the root down to the leaf level parent
+    coordinates: Vec<NodeCoordinates>,
+    /// position of the leaf the proof starts from
+    leaf: (Level, Index),
+    /// siblings given by the proof, from the leaf level up to the root
+    sibling_nodes: Vec<NodeCoordinates>,
+    /// number of items of the path consumed so far
+    step: usize,
+    hash: CryptoHash,
+    walk: PathWalk,
+    hashes_computed: usize,
+    hashes_cached: usize,
+    error: Option<BatchProofError>,
+}
+
+impl<'a> PendingProof<'a> {
+    fn new(proof: &'a MerklePath, item_hash: CryptoHash) -> Self {
+        let (_, coordinates) = node_coordinates(proof);
+        let (leaf, sibling_nodes) = if proof.is_empty() {
+            ((0, 0), Vec::new())
+        } else {
+            let (leaf_index, sibling_nodes) = sibling_nodes(proof, &coordinates);
+            ((proof.len(), leaf_index), sibling_nodes)
+        };
+        Self {
+            proof,
+            coordinates,
+            leaf,
+            sibling_nodes,
+            step: 0,
+            hash: item_hash,
+            walk: PathWalk::default(),
+            hashes_computed: 0,
+            hashes_cached: 0,
+            error: None,
+        }
+    }
+
+    /// Coordinates of the node the next step computes, if the proof is still going.
+    fn next_node(&self) -> Option<(Level, Index)> {
+        if self.error.is_some() || self.step >= self.proof.len() {
+            return None;
+        }
+        let NodeCoordinates { level, index, .. } =
+            &self.coordinates[self.proof.len() - self.step - 1];
+        Some((*level, *index))
+    }
+
+    /// The 64 bytes hashed into the next node.
+    fn next_input(&self) -> [u8; 64] {
+        let item = &self.proof[self.step];
+        let (left, right) = match item.direction {
+            Direction::Left => (item.hash, self.hash),
+            Direction::Right => (self.hash, item.hash),
+        };
+        let mut buffer = [0u8; 64];
+        buffer[..32].copy_from_slice(&left.0);
+        buffer[32..].copy_from_slice(&right.0);
+        buffer
+    }
+}
+
+impl<HF: HostFunctions> ProofBatchVerifier<HF> {
+    /// Same as [`Self::verify_batch`], hashing the proofs level by level: the nodes
+    /// every proof needs at a level are hashed with a single call to
+    /// [`HostFunctions::sha256_many`], and identical nodes are only hashed once.
+    /// Such a node is counted in the `hashes_computed` of the first proof that needs
+    /// it, so the report adds up to the number of hashes actually computed.
+    ///
+    /// Nodes are committed to the cache once every proof reached the root, so a
+    /// proof cannot rely on the nodes of another proof of the same batch.
+    ///
+    /// Fails with [`BatchProofError::HostHashCountMismatch`], leaving the cache
+    /// untouched, if [`HostFunctions::sha256_many`] does not return one hash per input.
+    pub fn verify_batch_by_level<'a>(
+        &mut self,
+        expected_root: CryptoHash,
+        proofs: impl IntoIterator<Item = (&'a MerklePath, CryptoHash)>,
+    ) -> Result<BatchReport, BatchProofError> {
+        self.pin_root(expected_root)?;
+        let mut pending = proofs
+            .into_iter()
+            .map(|(proof, item_hash)| PendingProof::new(proof, item_hash))
+            .collect::<Vec<_>>();
+
+        let depth = pending.iter().map(|p| p.proof.len()).max().unwrap_or(0);
+        for level in (0..depth).rev() {
+            // proofs that have to hash a node on this level, with the input to hash
+            let mut requests = Vec::new();
+            let mut inputs = Vec::new();
+            let mut input_positions = HashMap::new();
+            for (position, proof) in pending.iter_mut().enumerate() {
+                let node = match proof.next_node() {
+                    Some(node) if node.0 == level => node,
+                    _ => continue,
+                };
+                match self.cached_nodes.reuse(&mut proof.walk, node) {
+                    Some(cached) => {
+                        proof.hash = cached;
+                        proof.hashes_cached += 1;
+                        proof.step += 1;
+                    }
+                    None => {
+                        let input = proof.next_input();
+                        let (input_position, first) = match input_positions.get(&input) {
+                            Some(input_position) => (*input_position, false),
+                            None => {
+                                inputs.push(input);
+                                input_positions.insert(input, inputs.len() - 1);
+                                (inputs.len() - 1, true)
+                            }
+                        };
+                        requests.push((position, input_position, first));
+                    }
+                }
+            }
+            if requests.is_empty() {
+                continue;
+            }
+
+            let hashes = HF::sha256_many(&inputs.iter().map(|i| &i[..]).collect::<Vec<_>>());
+            if hashes.len() != inputs.len() {
+                return Err(BatchProofError::HostHashCountMismatch {
+                    inputs: inputs.len(),
+                    hashes: hashes.len(),
+                });
+            }
+            for (position, input_position, first) in requests {
+                let proof = &mut pending[position];
+                let node = proof.next_node().expect("proof has a pending node");
+                let hash = CryptoHash(hashes[input_position]);
+                proof.hash = hash;
+                if first {
+                    proof.hashes_computed += 1;
+                }
+                proof.step += 1;
+                if let Err(error) = self.cached_nodes.record(&mut proof.walk, node, hash) {
+                    proof.error = Some(error);
+                }
+            }
+        }
+
+        let proofs = pending
+            .into_iter()
+            .map(|proof| {
+                let result = match proof.error {
+                    Some(error) => Err(error),
+                    None if proof.hash != expected_root => Err(BatchProofError::RootMismatch {
+                        expected: expected_root,
+                        computed: proof.hash,
+                    }),
+                    None => {
+                        self.cached_nodes.commit(
+                            proof.walk.computed_nodes,
+                            &proof.walk.used_nodes,
+                            proof.leaf,
+                        );
+                        self.cached_nodes.prove_siblings(&proof.sibling_nodes);
+                        Ok(proof.hash)
+                    }
+                };
+                ProofReport::new(result, proof.hashes_computed, proof.hashes_cached)
+            })
+            .collect();
+        Ok(BatchReport { proofs })
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::test_utils::{item_hash, merklize, MockedHostFunctions};
+
+    static BATCH_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static BATCH_INPUTS: AtomicUsize = AtomicUsize::new(0);
+
+    struct BatchingHostFunctions;
+    impl HostFunctions for BatchingHostFunctions {
+        fn sha256(data: &[u8]) -> [u8; 32] {
+            MockedHostFunctions::sha256(data)
+        }
+
+        fn sha256_many(inputs: &[&[u8]]) -> Vec<[u8; 32]> {
+            BATCH_CALLS.fetch_add(1, Ordering::SeqCst);
+            BATCH_INPUTS.fetch_add(inputs.len(), Ordering::SeqCst);
+            inputs.iter().map(|input| Self::sha256(input)).collect()
+        }
+    }
+
+    #[test]
+    fn test_verify_batch_by_level() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+        let item_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+
+        let mut verifier = ProofBatchVerifier::<BatchingHostFunctions>::new();
+        let report = verifier
+            .verify_batch_by_level(
+                root_hash,
+                merkle_proofs.iter().zip(item_hashes.iter().copied()),
+            )
+            .unwrap();
+        assert!(report.is_valid());
+        // one call per level, each of the 7 inner nodes is only hashed once
+        assert_eq!(BATCH_CALLS.load(Ordering::SeqCst), 3);
+        assert_eq!(BATCH_INPUTS.load(Ordering::SeqCst), 7);
+        assert_eq!(report.hashes_computed(), 7);
+        // the leaf level parent of leaves 0 and 1 is charged to the proof of leaf 0
+        assert_eq!(report.proofs[0].hashes_computed, 3);
+        assert_eq!(report.proofs[1].hashes_computed, 0);
+
+        // same outcome and cache as verifying the proofs one by one
+        let mut sequential = ProofBatchVerifier::<MockedHostFunctions>::new();
+        sequential
+            .verify_all(
+                root_hash,
+                merkle_proofs.iter().zip(item_hashes.iter().copied()),
+            )
+            .unwrap();
+        assert_eq!(verifier.cached_nodes.inner, sequential.cached_nodes.inner);
+
+        // cached nodes are used, and wrong items are reported
+        let report = verifier
+            .verify_batch_by_level(
+                root_hash,
+                [
+                    (&merkle_proofs[0], item_hashes[0]),
+                    (&merkle_proofs[5], item_hash(&42)),
+                    (&merkle_proofs[6], item_hashes[6]),
+                ],
+            )
+            .unwrap();
+        assert_eq!(
+            report
+                .failures()
+                .map(|(position, _)| position)
+                .collect::<Vec<_>>(),
+            [1]
+        );
+        assert!(matches!(
+            report.proofs[1].error,
+            Some(BatchProofError::CacheMismatch { .. })
+        ));
+        assert_eq!(
+            (
+                report.proofs[0].hashes_computed,
+                report.proofs[0].hashes_cached
+            ),
+            (1, 2)
+        );
+    }
+
+    #[test]
+    fn test_verify_batch_by_level_uneven_paths() {
+        let elements = &[1, 2, 3, 4, 5];
+        let (root_hash, merkle_proofs) = merklize(elements);
+        let empty_proof: MerklePath = Vec::new();
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        let mut proofs = merkle_proofs
+            .iter()
+            .zip(elements.iter().map(item_hash))
+            .collect::<Vec<_>>();
+        proofs.push((&empty_proof, item_hash(&1)));
+        let report = verifier.verify_batch_by_level(root_hash, proofs).unwrap();
+        assert_eq!(
+            report
+                .failures()
+                .map(|(position, _)| position)
+                .collect::<Vec<_>>(),
+            [5]
+        );
+    }
+
+    #[test]
+    fn test_verify_batch_by_level_short_host_output() {
+        struct DroppingHostFunctions;
+        impl HostFunctions for DroppingHostFunctions {
+            fn sha256(data: &[u8]) -> [u8; 32] {
+                MockedHostFunctions::sha256(data)
+            }
+
+            fn sha256_many(inputs: &[&[u8]]) -> Vec<[u8; 32]> {
+                inputs[1..]
+                    .iter()
+                    .map(|input| Self::sha256(input))
+                    .collect()
+            }
+        }
+
+        let elements = &[1, 2, 3, 4];
+        let (root_hash, merkle_proofs) = merklize(elements);
+        let item_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+
+        let mut verifier = ProofBatchVerifier::<DroppingHostFunctions>::new();
+        assert_eq!(
+            verifier.verify_batch_by_level(
+                root_hash,
+                merkle_proofs.iter().zip(item_hashes.iter().copied()),
+            ),
+            Err(BatchProofError::HostHashCountMismatch {
+                inputs: 2,
+                hashes: 1
+            })
+        );
+        assert_eq!(verifier.cache_len(), 0);
+    }
+}
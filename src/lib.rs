@@ -3,34 +3,105 @@
 //! ## Introduction
 //! The purpose of this create is to allow light clients to verify proofs in batches.
 //!
+//! ## Host functions
+//! Hashing is delegated to an implementation of [`HostFunctions`]. The following
+//! ones are available behind cargo features:
+//! - `sha2`: `host_functions::Sha2HostFunctions`, a pure Rust implementation.
+//! - `near-sdk`: `host_functions::NearHostFunctions`, for NEAR contracts.
+//! - `sp-io`: `host_functions::SubstrateHostFunctions`, for Substrate runtimes.
+//!
+//! Light client headers also need [`HostFunctions::ed25519_verify`], which
+//! `SubstrateHostFunctions` provides, and `Sha2HostFunctions` with the
+//! `ed25519-dalek` feature.
+//!
+//! ## `no_std`
+//! The crate builds without `std` when its default features are disabled. Merkle
+//! primitives are defined in [`primitives`]; the `near` feature adds conversions
+//! from and into the types of `near-primitives`.
+//!
+//! Only `cargo build --no-default-features` checks the `no_std` build: the tests
+//! need `std`, so `cargo test --no-default-features` compiles none of them.
+//!
+//! ## Parallel verification
+//! The `rayon` feature adds [`ProofBatchVerifier::verify_batch_parallel`], which
+//! hashes the proofs of a batch on the rayon thread pool.
+//!
+//! ## Light client proofs
+//! [`LightClientVerifier`] checks NEAR execution outcome proofs end to end, from
+//! the outcome up to the `block_merkle_root` of a trusted head, and
+//! [`BlockMerkleVerifier`] proves past blocks by their ordinal against it.
+//! [`ChunkPartsVerifier`] checks the erasure-coded parts of a chunk against its
+//! `encoded_merkle_root`.
+//!
+//! The head itself is moved forward by [`LightClientHeaderVerifier`], which checks
+//! the approvals of the block producers on every header.
+//!
+//! ## State proofs
+//! [`StateProofVerifier`] looks keys up in NEAR's state trie out of state proofs,
+//! caching the trie nodes it verified by their hash.
+//!
 
 #![cfg_attr(not(feature = "std"), no_std)]
 
 extern crate no_std_compat as std;
 
-use core::marker::PhantomData;
-use std::{collections::HashMap, vec::Vec};
-mod host_functions;
-use borsh::BorshSerialize;
-use host_functions::HostFunctions;
-
-use near_primitives::{
-    hash::CryptoHash,
-    merkle::{Direction, MerklePath},
-};
+use core::{iter, marker::PhantomData, ops::RangeBounds};
+use std::vec::Vec;
+pub mod block_merkle;
+mod cache;
+pub mod chunk_parts;
+mod error;
+pub mod host_functions;
+mod level_batch;
+pub mod light_client;
+pub mod light_client_header;
+pub mod multi_root;
+pub mod multiproof;
+#[cfg(feature = "near")]
+pub mod near;
+#[cfg(feature = "rayon")]
+mod parallel;
+pub mod position;
+mod positioned;
+pub mod primitives;
+pub mod prover;
+pub mod report;
+mod snapshot;
+pub mod state_proof;
+#[cfg(all(test, feature = "std"))]
+mod test_utils;
+pub use block_merkle::BlockMerkleVerifier;
+pub use cache::CacheCapacity;
+use cache::{CachedNodes, PathWalk};
+pub use chunk_parts::ChunkPartsVerifier;
+pub use error::BatchProofError;
+pub use host_functions::HostFunctions;
+pub use light_client::{LightClientExecutionProof, LightClientVerifier};
+pub use light_client_header::{LightClientBlock, LightClientHeaderVerifier};
+pub use multi_root::{MultiRootVerifier, ShardId};
+pub use multiproof::MultiProof;
+use position::tree_height;
+pub use position::PathPositions;
+pub use primitives::{CryptoHash, Direction, MerklePath, MerklePathItem};
+pub use prover::{merklize, MerkleTree};
+pub use report::{BatchReport, ProofReport};
+pub use state_proof::StateProofVerifier;
 
 type Level = usize;
 type Index = usize;
 type LeafIndex = usize;
+type ComputedNodes = Vec<((Level, Index), CryptoHash)>;
 
 /// ProofBatchVerifier verifies merkle proofs and maintains a cache
 /// of intermediate computations to avoid having to spend too many
 /// CPU cycles in vain.
 ///
-/// ## Note: it's important that all the proofs belong to the same shard.
+/// ## Note: it's important that all the proofs belong to the same tree.
+/// Use a [`MultiRootVerifier`] to verify proofs of several shards or blocks.
 #[derive(Debug, PartialEq, Eq)]
 pub struct ProofBatchVerifier<HF: HostFunctions> {
     cached_nodes: CachedNodes,
+    trusted_root: Option<CryptoHash>,
     _hf: PhantomData<HF>,
 }
 
@@ -41,242 +112,594 @@ pub struct NodeCoordinates {
     hash: Option<CryptoHash>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-struct CachedNodes {
-    inner: HashMap<(Level, Index), CryptoHash>,
-    path_item_cache_mapping: HashMap<LeafIndex, Vec<(Level, Index)>>,
+/// Outcome of recomputing a merkle path, before anything is committed to the cache.
+struct ComputedPath {
+    root_hash: CryptoHash,
+    /// position of the leaf the path starts from
+    leaf: (Level, Index),
+    /// nodes on the path from the leaf to the root that were not cached yet
+    computed_nodes: ComputedNodes,
+    /// cached nodes the path went through
+    used_nodes: Vec<(Level, Index)>,
+    /// siblings provided by the proof, from the leaf level up to the root
+    sibling_nodes: Vec<NodeCoordinates>,
 }
 
-impl CachedNodes {
-    fn new() -> Self {
-        Self {
-            inner: HashMap::new(),
-            path_item_cache_mapping: HashMap::new(),
-        }
-    }
-
-    fn extend_from_given(&mut self, given_nodes: &[NodeCoordinates], leaf_index: LeafIndex) {
-        if given_nodes.len() == 0 {
-            return;
-        }
+/// Number of nodes hashed while recomputing a path, and of nodes served by the cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct HashCounts {
+    computed: usize,
+    cached: usize,
+}
 
-        given_nodes.iter().for_each(|node| {
-            let NodeCoordinates { index, level, hash } = node;
-            if self.inner.get(&(*level, *index)).is_some() {
-                return;
-            }
-            self.inner.insert((*level, *index), hash.unwrap());
-            let e = self
-                .path_item_cache_mapping
-                .entry(leaf_index)
-                .or_insert_with(Vec::new);
-            e.push((*level, *index));
-        });
+impl<HF: HostFunctions> Default for ProofBatchVerifier<HF> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl<HF: HostFunctions> ProofBatchVerifier<HF> {
     pub fn new() -> Self {
+        Self::with_capacity(CacheCapacity::Unbounded)
+    }
+
+    /// Creates a verifier whose cache never grows past `capacity`.
+    pub fn with_capacity(capacity: CacheCapacity) -> Self {
         Self {
-            cached_nodes: CachedNodes::new(),
-            _hf: PhantomData::default(),
+            cached_nodes: CachedNodes::with_capacity(capacity),
+            trusted_root: None,
+            _hf: PhantomData,
         }
     }
 
+    /// Number of nodes currently cached.
+    pub fn cache_len(&self) -> usize {
+        self.cached_nodes.len()
+    }
+
+    /// Approximate number of bytes used by the cached nodes.
+    pub fn cache_size_bytes(&self) -> usize {
+        self.cached_nodes.size_bytes()
+    }
+
     /// Computes the root hash of a given merkle proof and item hash
     /// It will update the cache of intermediate nodes so that they do not have
-    /// to be recomputed
+    /// to be recomputed, once the verifier is pinned to a trusted root
+    ///
+    /// Panics if the proof conflicts with the cached nodes. See
+    /// [`Self::try_calculate_root_hash`] for the fallible version.
     pub fn calculate_root_hash(&mut self, proof: &MerklePath, item_hash: CryptoHash) -> CryptoHash {
-        // trivial example, where proof is empty
-        if proof.len() == 0 {
-            return CryptoHash::default();
+        self.try_calculate_root_hash(proof, item_hash)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Computes the root hash of a given merkle proof and item hash
+    /// It will update the cache of intermediate nodes so that they do not have
+    /// to be recomputed
+    ///
+    /// Nodes are only cached once the verifier is pinned to a trusted root, and a
+    /// proof that lands on a different root is rejected and leaves the cache
+    /// untouched. Without a pinned root, nothing tells a forged path apart, so the
+    /// root is computed without caching anything.
+    ///
+    /// An empty proof is the proof of a single-leaf tree, whose root is the item hash.
+    pub fn try_calculate_root_hash(
+        &mut self,
+        proof: &MerklePath,
+        item_hash: CryptoHash,
+    ) -> Result<CryptoHash, BatchProofError> {
+        self.commit_root_hash(proof, item_hash, &mut HashCounts::default())
+    }
+
+    /// Recomputes the path of `proof` and commits it to the cache if it lands on
+    /// the trusted root, keeping track of the hashes that were needed.
+    fn commit_root_hash(
+        &mut self,
+        proof: &MerklePath,
+        item_hash: CryptoHash,
+        counts: &mut HashCounts,
+    ) -> Result<CryptoHash, BatchProofError> {
+        self.commit_root_hash_with(proof, item_hash, counts, CryptoHash::hash_pair::<HF>)
+    }
+
+    /// Same as [`Self::commit_root_hash`], hashing pairs of nodes with `hash_pair`.
+    fn commit_root_hash_with(
+        &mut self,
+        proof: &MerklePath,
+        item_hash: CryptoHash,
+        counts: &mut HashCounts,
+        hash_pair: impl Fn(&CryptoHash, &CryptoHash) -> CryptoHash,
+    ) -> Result<CryptoHash, BatchProofError> {
+        let ComputedPath {
+            root_hash,
+            leaf,
+            computed_nodes,
+            used_nodes,
+            sibling_nodes,
+        } = self.compute_root_hash_with(proof, item_hash, counts, hash_pair)?;
+        match self.trusted_root {
+            // nodes of an unchecked root must not be trusted by later proofs
+            None => return Ok(root_hash),
+            Some(trusted_root) if trusted_root != root_hash => {
+                return Err(BatchProofError::RootMismatch {
+                    expected: trusted_root,
+                    computed: root_hash,
+                });
+            }
+            Some(_) => {}
         }
+        self.cached_nodes.commit(computed_nodes, &used_nodes, leaf);
+        self.cached_nodes.prove_siblings(&sibling_nodes);
 
-        // the first element is somewhat different, since the caller is passing the item's hash
-        let (_, node_coordinates_to_calculate) = self.get_node_coordinates(proof);
-        let nodes_to_calculate = node_coordinates_to_calculate.len();
+        Ok(root_hash)
+    }
 
-        let sibling_item = &proof[0];
+    /// Verifies that the given merkle proof and item hash yield `expected_root`.
+    ///
+    /// The verifier gets pinned to `expected_root` on first use, so the cache can
+    /// only ever be seeded with nodes that belong to that tree.
+    ///
+    /// An empty proof is only valid for a single-leaf tree, whose root is the item
+    /// hash. Use [`Self::verify_at_index`] when the size of the tree is known.
+    pub fn verify(
+        &mut self,
+        expected_root: CryptoHash,
+        proof: &MerklePath,
+        item_hash: CryptoHash,
+    ) -> Result<(), BatchProofError> {
+        self.pin_root(expected_root)?;
+        self.try_calculate_root_hash(proof, item_hash).map(|_| ())
+    }
 
-        // calculate the hash for the leaf level by hashing the item_hash given and its sibling (provided in the proof)
-        let hash = match sibling_item.direction {
-            Direction::Left => hash_borsh::<_, HF>(&(sibling_item.hash, item_hash)),
-            Direction::Right => hash_borsh::<_, HF>(&(item_hash, sibling_item.hash)),
-        };
+    /// Same as [`Self::verify`], also checking that the item is the leaf at
+    /// `leaf_index` of a tree of `tree_size` leaves, e.g. the k-th receipt of a chunk.
+    ///
+    /// Fails with [`BatchProofError::InvalidPathShape`] if the directions of the
+    /// path do not lead to that leaf, see [`PathPositions`], and with
+    /// [`BatchProofError::EmptyProof`] if the path is empty but the tree has more
+    /// than one leaf.
+    pub fn verify_at_index(
+        &mut self,
+        expected_root: CryptoHash,
+        proof: &MerklePath,
+        item_hash: CryptoHash,
+        leaf_index: LeafIndex,
+        tree_size: usize,
+    ) -> Result<(), BatchProofError> {
+        if proof.is_empty() && tree_size > 1 {
+            return Err(BatchProofError::EmptyProof);
+        }
+        PathPositions::from_path(proof, leaf_index, tree_size)?;
+        self.verify(expected_root, proof, item_hash)
+    }
+
+    /// Verifies a batch of `(proof, item_hash)` pairs against `expected_root`,
+    /// stopping at the first proof that fails.
+    pub fn verify_all<'a>(
+        &mut self,
+        expected_root: CryptoHash,
+        proofs: impl IntoIterator<Item = (&'a MerklePath, CryptoHash)>,
+    ) -> Result<(), BatchProofError> {
+        self.pin_root(expected_root)?;
+        proofs
+            .into_iter()
+            .try_for_each(|(proof, item_hash)| self.verify(expected_root, proof, item_hash))
+    }
+
+    /// Verifies every `(proof, item_hash)` pair against `expected_root`, without
+    /// stopping at the first failure.
+    ///
+    /// Each proof is checked on its own: the valid ones seed the cache, while the
+    /// [`BatchReport`] tells which ones failed and why. Only fails as a whole if the
+    /// verifier is pinned to another root.
+    pub fn verify_batch<'a>(
+        &mut self,
+        expected_root: CryptoHash,
+        proofs: impl IntoIterator<Item = (&'a MerklePath, CryptoHash)>,
+    ) -> Result<BatchReport, BatchProofError> {
+        self.pin_root(expected_root)?;
+        let proofs = proofs
+            .into_iter()
+            .map(|(proof, item_hash)| {
+                let mut counts = HashCounts::default();
+                let result = self.commit_root_hash(proof, item_hash, &mut counts);
+                ProofReport::new(result, counts.computed, counts.cached)
+            })
+            .collect();
+        Ok(BatchReport { proofs })
+    }
+
+    /// Verifies all the leaves of a [`MultiProof`] against `expected_root` in a
+    /// single pass. The verifier gets pinned to `expected_root`, like in [`Self::verify`].
+    pub fn verify_multiproof(
+        &mut self,
+        expected_root: CryptoHash,
+        multiproof: &MultiProof,
+        leaf_hashes: &[CryptoHash],
+    ) -> Result<(), BatchProofError> {
+        self.pin_root(expected_root)?;
+        multiproof.verify::<HF>(expected_root, leaf_hashes)
+    }
+
+    /// Returns the root this verifier is pinned to, if any.
+    pub fn trusted_root(&self) -> Option<CryptoHash> {
+        self.trusted_root
+    }
+
+    /// Marks the current state of the cache, so that every node committed
+    /// afterwards can be dropped again with [`Self::rollback`].
+    ///
+    /// Nodes are always committed only once their own proof succeeded; a
+    /// checkpoint extends that to a group of proofs that must pass together.
+    /// Taking a new checkpoint keeps everything committed so far.
+    pub fn checkpoint(&mut self) {
+        self.cached_nodes.checkpoint();
+    }
 
-        let NodeCoordinates { index, level, .. } =
-            &node_coordinates_to_calculate[nodes_to_calculate - 0 - 1];
-        let cached_value = self.cached_nodes.inner.get(&(*level, *index));
+    /// Removes every node committed since the last [`Self::checkpoint`].
+    /// Does nothing if no checkpoint was taken.
+    pub fn rollback(&mut self) {
+        self.cached_nodes.rollback();
+    }
+
+    /// Removes the cached nodes that only the leaf at `leaf_index` of a tree of
+    /// `tree_size` leaves contributed, e.g. once the item it proves was processed.
+    /// Nodes shared with other leaves are kept.
+    ///
+    /// The size of the tree is needed to tell apart leaves of unbalanced trees, which
+    /// do not all sit on the same level. Leaves outside of the tree are ignored.
+    /// Forgetting a leaf cannot be undone by [`Self::rollback`].
+    pub fn forget_leaf(&mut self, leaf_index: LeafIndex, tree_size: usize) {
+        if let Some(leaf) = leaf_coordinates(leaf_index, tree_size) {
+            self.cached_nodes.forget_leaf(leaf);
+        }
+    }
+
+    /// Same as [`Self::forget_leaf`], for every leaf in `leaves`.
+    pub fn forget_leaves(&mut self, leaves: impl RangeBounds<LeafIndex>, tree_size: usize) {
+        // only cached leaves can be forgotten, which are far fewer than the leaves of
+        // a large tree
+        let forgotten = self
+            .cached_nodes
+            .path_item_cache_mapping
+            .keys()
+            .filter(|leaf| {
+                leaf_at_coordinates(**leaf, tree_size)
+                    .is_some_and(|leaf_index| leaves.contains(&leaf_index))
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        for leaf in forgotten {
+            self.cached_nodes.forget_leaf(leaf);
+        }
+    }
 
-        match cached_value {
+    fn pin_root(&mut self, root: CryptoHash) -> Result<(), BatchProofError> {
+        match self.trusted_root {
             None => {
-                self.cached_nodes.inner.insert((*level, *index), hash);
-            }
-            Some(parent_hash) => {
-                // ensure that, if the value was cached it matches the calculation made above
-                // this is important, otherwise when most of the intermediates nodes are cached, if this check
-                // is not made, a wrong proof could be passed and stil "yield" the right root hash
-                assert_eq!(parent_hash, &hash);
+                self.trusted_root = Some(root);
+                Ok(())
             }
+            Some(trusted_root) if trusted_root == root => Ok(()),
+            Some(trusted_root) => Err(BatchProofError::UnexpectedRoot {
+                pinned: trusted_root,
+                given: root,
+            }),
         }
+    }
 
-        proof
-            .iter()
-            .enumerate()
-            .skip(1) // skip the parent
-            .fold(hash, |mut hash, (item_idx, merkle_path_item)| {
+    /// Recomputes the path of `proof` up to the root without touching the cache.
+    fn compute_root_hash(
+        &self,
+        proof: &MerklePath,
+        item_hash: CryptoHash,
+        counts: &mut HashCounts,
+    ) -> Result<ComputedPath, BatchProofError> {
+        self.compute_root_hash_with(proof, item_hash, counts, CryptoHash::hash_pair::<HF>)
+    }
+
+    /// Same as [`Self::compute_root_hash`], hashing pairs of nodes with `hash_pair`.
+    fn compute_root_hash_with(
+        &self,
+        proof: &MerklePath,
+        item_hash: CryptoHash,
+        counts: &mut HashCounts,
+        hash_pair: impl Fn(&CryptoHash, &CryptoHash) -> CryptoHash,
+    ) -> Result<ComputedPath, BatchProofError> {
+        // the item is the root of a single-leaf tree
+        if proof.is_empty() {
+            return Ok(ComputedPath {
+                root_hash: item_hash,
+                leaf: (0, 0),
+                computed_nodes: Vec::new(),
+                used_nodes: Vec::new(),
+                sibling_nodes: Vec::new(),
+            });
+        }
+
+        let (_, node_coordinates_to_calculate) = self.get_node_coordinates(proof);
+        let nodes_to_calculate = node_coordinates_to_calculate.len();
+        let mut walk = PathWalk::default();
+
+        let root_hash = proof.iter().enumerate().try_fold(
+            item_hash,
+            |hash, (item_idx, merkle_path_item)| {
                 let NodeCoordinates { index, level, .. } =
                     &node_coordinates_to_calculate[nodes_to_calculate - item_idx - 1];
-
-                let cached_value = self.cached_nodes.inner.get(&(*level, *index));
-                match cached_value {
-                    None => {
-                        match merkle_path_item.direction {
-                            Direction::Left => {
-                                hash = CryptoHash::hash_borsh(&(merkle_path_item.hash, hash))
-                            }
-                            Direction::Right => {
-                                hash = CryptoHash::hash_borsh(&(hash, merkle_path_item.hash))
-                            }
-                        };
-                        // update the cache
-                        self.cached_nodes.inner.insert((*level, *index), hash);
-                    }
-                    Some(cached_value) => {
-                        hash = *cached_value;
+                let (hash, cached) = self.cached_nodes.step(&mut walk, (*level, *index), || {
+                    counts.computed += 1;
+                    match merkle_path_item.direction {
+                        Direction::Left => hash_pair(&merkle_path_item.hash, &hash),
+                        Direction::Right => hash_pair(&hash, &merkle_path_item.hash),
                     }
+                })?;
+                if cached {
+                    counts.cached += 1;
                 }
+                Ok(hash)
+            },
+        )?;
 
-                hash
-            })
+        let (leaf_index, sibling_nodes) = sibling_nodes(proof, &node_coordinates_to_calculate);
+        let PathWalk {
+            computed_nodes,
+            used_nodes,
+            ..
+        } = walk;
+        Ok(ComputedPath {
+            root_hash,
+            leaf: (proof.len(), leaf_index),
+            computed_nodes,
+            used_nodes,
+            sibling_nodes,
+        })
     }
 
     /// Updates the cache with all the values that are given on a merkle proof
-    pub fn update_cache<'a>(&mut self, proofs: impl Iterator<Item = &'a MerklePath>) {
-        proofs.for_each(|proof| {
-            let (given_nodes, _) = self.get_node_coordinates(proof);
-            let leaf_index = given_nodes.last().unwrap().index;
-            self.cached_nodes
-                .extend_from_given(&given_nodes[0..(given_nodes.len() - 1)], leaf_index);
-        });
+    ///
+    /// Panics if any of the proofs is empty or does not hash to `trusted_root`.
+    /// See [`Self::try_update_cache`] for the fallible version.
+    pub fn update_cache<'a>(
+        &mut self,
+        trusted_root: CryptoHash,
+        proofs: impl IntoIterator<Item = (&'a MerklePath, CryptoHash)>,
+    ) {
+        self.try_update_cache(trusted_root, proofs)
+            .unwrap_or_else(|e| panic!("{}", e))
     }
 
+    /// Updates the cache with all the values that are given on a merkle proof
+    ///
+    /// Each proof is recomputed from its item hash first, and its nodes are only
+    /// committed once the path is known to hash to `trusted_root`. This way a
+    /// forged path can never seed the cache.
+    pub fn try_update_cache<'a>(
+        &mut self,
+        trusted_root: CryptoHash,
+        proofs: impl IntoIterator<Item = (&'a MerklePath, CryptoHash)>,
+    ) -> Result<(), BatchProofError> {
+        self.pin_root(trusted_root)?;
+        for (proof, item_hash) in proofs {
+            // a single-leaf tree has no node to seed the cache with
+            if proof.is_empty() {
+                return Err(BatchProofError::EmptyProof);
+            }
+            let ComputedPath {
+                root_hash,
+                leaf,
+                computed_nodes,
+                used_nodes,
+                sibling_nodes,
+            } = self.compute_root_hash(proof, item_hash, &mut HashCounts::default())?;
+            if root_hash != trusted_root {
+                return Err(BatchProofError::RootMismatch {
+                    expected: trusted_root,
+                    computed: root_hash,
+                });
+            }
+            self.cached_nodes.commit(computed_nodes, &used_nodes, leaf);
+            self.cached_nodes.extend_from_given(&sibling_nodes, leaf)?;
+        }
+        Ok(())
+    }
+
+    /// Coordinates of the nodes of `proof`, assuming a perfect tree whose depth is
+    /// the length of the path. See [`PathPositions`] for the exact positions in
+    /// unbalanced trees.
     pub fn get_node_coordinates(
         &self,
         proof: &MerklePath,
     ) -> (Vec<NodeCoordinates>, Vec<NodeCoordinates>) {
-        let tree_depth = proof.len();
-        proof
-            .iter()
-            .rev()
-            .fold(
-                ((Vec::new(), Vec::new()), 0, 0, 0),
-                |(
-                    (mut node_coordinates_given, mut node_coordinates_to_calculate),
-                    mut depth,
-                    mut idx_given,
-                    mut idx_to_calculate,
-                ),
-                 el| {
-                    depth += 1;
-                    match depth {
-                        1 => {
-                            node_coordinates_to_calculate.push(NodeCoordinates {
-                                index: 0,
-                                level: 0,
-                                hash: None,
-                            });
+        node_coordinates(proof)
+    }
+}
 
-                            match el.direction {
-                                Direction::Left => {
-                                    idx_to_calculate = 1;
-                                }
-                                Direction::Right => {
-                                    idx_given = 1;
-                                    idx_to_calculate = 0;
-                                }
+/// Splits the nodes of a proof into the ones given by the proof and the ones that
+/// have to be calculated, see [`ProofBatchVerifier::get_node_coordinates`].
+pub(crate) fn node_coordinates(proof: &MerklePath) -> (Vec<NodeCoordinates>, Vec<NodeCoordinates>) {
+    let tree_depth = proof.len();
+    proof
+        .iter()
+        .rev()
+        .fold(
+            ((Vec::new(), Vec::new()), 0, 0, 0),
+            |(
+                (mut node_coordinates_given, mut node_coordinates_to_calculate),
+                mut depth,
+                mut idx_given,
+                mut idx_to_calculate,
+            ),
+             el| {
+                depth += 1;
+                match depth {
+                    1 => {
+                        node_coordinates_to_calculate.push(NodeCoordinates {
+                            index: 0,
+                            level: 0,
+                            hash: None,
+                        });
+
+                        match el.direction {
+                            Direction::Left => {
+                                idx_to_calculate = 1;
                             }
-                            // edge case depth == 1
-                            node_coordinates_given.push(NodeCoordinates {
-                                index: idx_given,
-                                level: depth,
-                                hash: Some(el.hash),
-                            });
-                            if depth == tree_depth {
-                                node_coordinates_given.push(NodeCoordinates {
-                                    index: idx_given ^ 1,
-                                    level: depth,
-                                    hash: Some(el.hash),
-                                });
-                            } else {
-                                node_coordinates_to_calculate.push(NodeCoordinates {
-                                    index: idx_to_calculate,
-                                    level: depth,
-                                    hash: None,
-                                });
+                            Direction::Right => {
+                                idx_given = 1;
+                                idx_to_calculate = 0;
                             }
                         }
-                        depth if depth == tree_depth => {
-                            idx_to_calculate *= 2;
-                            idx_given = idx_to_calculate;
-                            // both nodes are given on the leaf level
-                            node_coordinates_given.push(NodeCoordinates {
-                                index: idx_given,
-                                level: depth,
-                                hash: Some(el.hash),
-                            });
+                        // edge case depth == 1
+                        node_coordinates_given.push(NodeCoordinates {
+                            index: idx_given,
+                            level: depth,
+                            hash: Some(el.hash),
+                        });
+                        if depth == tree_depth {
                             node_coordinates_given.push(NodeCoordinates {
                                 index: idx_given ^ 1,
                                 level: depth,
                                 hash: Some(el.hash),
-                            })
-                        }
-                        depth => {
-                            // move to the children
-                            idx_to_calculate *= 2;
-                            idx_given = idx_to_calculate;
-                            match el.direction {
-                                Direction::Left => {
-                                    idx_to_calculate ^= 1;
-                                }
-                                Direction::Right => {
-                                    idx_given ^= 1;
-                                }
-                            }
-                            node_coordinates_given.push(NodeCoordinates {
-                                index: idx_given,
-                                level: depth,
-                                hash: Some(el.hash),
                             });
+                        } else {
                             node_coordinates_to_calculate.push(NodeCoordinates {
                                 index: idx_to_calculate,
                                 level: depth,
                                 hash: None,
                             });
                         }
-                    };
-                    (
-                        (node_coordinates_given, node_coordinates_to_calculate),
-                        depth,
-                        idx_given,
-                        idx_to_calculate,
-                    )
-                },
-            )
-            .0
+                    }
+                    depth if depth == tree_depth => {
+                        idx_to_calculate *= 2;
+                        idx_given = idx_to_calculate;
+                        // both nodes are given on the leaf level
+                        node_coordinates_given.push(NodeCoordinates {
+                            index: idx_given,
+                            level: depth,
+                            hash: Some(el.hash),
+                        });
+                        node_coordinates_given.push(NodeCoordinates {
+                            index: idx_given ^ 1,
+                            level: depth,
+                            hash: Some(el.hash),
+                        })
+                    }
+                    depth => {
+                        // move to the children
+                        idx_to_calculate *= 2;
+                        idx_given = idx_to_calculate;
+                        match el.direction {
+                            Direction::Left => {
+                                idx_to_calculate ^= 1;
+                            }
+                            Direction::Right => {
+                                idx_given ^= 1;
+                            }
+                        }
+                        node_coordinates_given.push(NodeCoordinates {
+                            index: idx_given,
+                            level: depth,
+                            hash: Some(el.hash),
+                        });
+                        node_coordinates_to_calculate.push(NodeCoordinates {
+                            index: idx_to_calculate,
+                            level: depth,
+                            hash: None,
+                        });
+                    }
+                };
+                (
+                    (node_coordinates_given, node_coordinates_to_calculate),
+                    depth,
+                    idx_given,
+                    idx_to_calculate,
+                )
+            },
+        )
+        .0
+}
+
+/// Position of the leaf at `leaf_index` of a tree of `tree_size` leaves, in the
+/// coordinates [`ProofBatchVerifier`] derives from its proof: the leaf sits at the
+/// level given by the length of its path, and its index is read from the directions.
+fn leaf_coordinates(leaf_index: LeafIndex, tree_size: usize) -> Option<(Level, Index)> {
+    let positions = PathPositions::new(leaf_index, tree_size).ok()?;
+    let index = positions
+        .siblings()
+        .iter()
+        .enumerate()
+        // a sibling on the left means the path goes through the right child
+        .fold(0, |index, (depth, (_, sibling_index))| {
+            index | usize::from(sibling_index % 2 == 0) << depth
+        });
+    Some((positions.siblings().len(), index))
+}
+
+/// Index of the leaf of a tree of `tree_size` leaves at `(level, index)`, the inverse
+/// of [`leaf_coordinates`].
+fn leaf_at_coordinates((level, index): (Level, Index), tree_size: usize) -> Option<LeafIndex> {
+    let height = tree_height(tree_size).ok()?;
+    // number of nodes on each level, from the leaves up to the root
+    let level_sizes = iter::successors(Some(tree_size), |size| Some(size.div_ceil(2)))
+        .take(height + 1)
+        .collect::<Vec<_>>();
+    let (mut node, mut directions) = (0, level);
+    for level_size in level_sizes.iter().rev().skip(1) {
+        node *= 2;
+        // a node without a sibling is carried up and takes no direction
+        if node + 1 < *level_size {
+            directions = directions.checked_sub(1)?;
+            node += (index >> directions) & 1;
+        }
     }
+    (directions == 0 && index.checked_shr(level as u32).unwrap_or(0) == 0).then_some(node)
 }
 
-fn hash_borsh<T: BorshSerialize, HF: HostFunctions>(items: &(T, T)) -> CryptoHash {
-    let serialized = items.try_to_vec().unwrap();
-    CryptoHash(HF::sha256(&serialized))
+/// Returns the index of the leaf a proof is for, along with the coordinates of the
+/// siblings given by the proof, from the leaf level up to the root.
+pub(crate) fn sibling_nodes(
+    proof: &MerklePath,
+    node_coordinates_to_calculate: &[NodeCoordinates],
+) -> (LeafIndex, Vec<NodeCoordinates>) {
+    let nodes_to_calculate = node_coordinates_to_calculate.len();
+    let NodeCoordinates { index, level, .. } =
+        &node_coordinates_to_calculate[nodes_to_calculate - 1];
+    let sibling_item = &proof[0];
+
+    // the item sits on the right whenever its sibling is on the left
+    let leaf_index = 2 * index
+        + match sibling_item.direction {
+            Direction::Left => 1,
+            Direction::Right => 0,
+        };
+    let sibling_nodes = proof
+        .iter()
+        .enumerate()
+        .map(|(item_idx, merkle_path_item)| {
+            let (level, index) = match item_idx {
+                0 => (level + 1, leaf_index),
+                _ => {
+                    let node = &node_coordinates_to_calculate[nodes_to_calculate - item_idx];
+                    (node.level, node.index)
+                }
+            };
+            NodeCoordinates {
+                index: index ^ 1,
+                level,
+                hash: Some(merkle_path_item.hash),
+            }
+        })
+        .collect();
+
+    (leaf_index, sibling_nodes)
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
-    use near_primitives::merkle::{compute_root_from_path_and_item, merklize, MerklePathItem};
-
     use super::*;
+    use crate::test_utils::{
+        compute_root_from_path_and_item, count_sha256_calls, item_hash, merklize, sha256_calls,
+        CountingHostFunctions, MockedHostFunctions,
+    };
 
     struct ExpectedResult {
         node_coordinates_given: Vec<NodeCoordinates>,
@@ -289,14 +712,6 @@ mod tests {
         }
     }
 
-    struct MockedHostFunctions;
-    impl HostFunctions for MockedHostFunctions {
-        fn sha256(data: &[u8]) -> [u8; 32] {
-            use sha2::Digest;
-            sha2::Sha256::digest(data).try_into().unwrap()
-        }
-    }
-
     #[test]
     fn test_get_nodes_to_be_calculated() {
         let cases = [
@@ -697,17 +1112,20 @@ mod tests {
         for (idx, element) in elements.iter().enumerate() {
             let merkle_proof = &merkle_proofs[idx];
             assert_eq!(
-                verifier.calculate_root_hash(merkle_proof, CryptoHash::hash_borsh(element)),
+                verifier.calculate_root_hash(merkle_proof, item_hash(element)),
                 root_hash
             );
         }
 
         // try with cache updated
-        verifier.update_cache(merkle_proofs.iter());
+        verifier.update_cache(
+            root_hash,
+            merkle_proofs.iter().zip(elements.iter().map(item_hash)),
+        );
         for (idx, element) in elements.iter().enumerate() {
             let merkle_proof = &merkle_proofs[idx];
             assert_eq!(
-                verifier.calculate_root_hash(merkle_proof, CryptoHash::hash_borsh(element)),
+                verifier.calculate_root_hash(merkle_proof, item_hash(element)),
                 root_hash
             );
         }
@@ -726,7 +1144,7 @@ mod tests {
         let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
         let merkle_proof = &merkle_proofs[0];
         assert_eq!(
-            verifier.calculate_root_hash(merkle_proof, CryptoHash::hash_borsh(&2)),
+            verifier.calculate_root_hash(merkle_proof, item_hash(&2)),
             root_hash
         );
     }
@@ -743,12 +1161,599 @@ mod tests {
 
         let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
         // try with cache updated
-        verifier.update_cache(merkle_proofs.iter());
+        verifier.update_cache(
+            root_hash,
+            merkle_proofs.iter().zip(elements.iter().map(item_hash)),
+        );
 
         let merkle_proof = &merkle_proofs[0];
         assert_eq!(
-            verifier.calculate_root_hash(merkle_proof, CryptoHash::hash_borsh(&2)),
+            verifier.calculate_root_hash(merkle_proof, item_hash(&2)),
             root_hash
         );
     }
+
+    #[test]
+    fn test_try_calculate_root_hash_wrong_items_with_loaded_cache() {
+        let elements = &[1, 2, 3, 4, 5];
+        let (root_hash, merkle_proofs) = merklize(elements);
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        verifier.update_cache(
+            root_hash,
+            merkle_proofs.iter().zip(elements.iter().map(item_hash)),
+        );
+
+        let result = verifier.try_calculate_root_hash(&merkle_proofs[0], item_hash(&2));
+        assert!(matches!(
+            result,
+            Err(BatchProofError::CacheMismatch {
+                level: 2,
+                index: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_try_update_cache_empty_proof() {
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        let empty_proof: MerklePath = Vec::new();
+        assert_eq!(
+            verifier.try_update_cache(
+                CryptoHash::default(),
+                [(&empty_proof, CryptoHash::default())]
+            ),
+            Err(BatchProofError::EmptyProof)
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        verifier
+            .verify_all(
+                root_hash,
+                merkle_proofs.iter().zip(elements.iter().map(item_hash)),
+            )
+            .unwrap();
+        assert_eq!(verifier.trusted_root(), Some(root_hash));
+
+        // a different root can no longer be used with this verifier
+        assert_eq!(
+            verifier.verify(CryptoHash::default(), &merkle_proofs[0], item_hash(&1)),
+            Err(BatchProofError::UnexpectedRoot {
+                pinned: root_hash,
+                given: CryptoHash::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_wrong_item_does_not_seed_cache() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        verifier
+            .verify(root_hash, &merkle_proofs[0], item_hash(&1))
+            .unwrap();
+        let cached_nodes = verifier.cached_nodes.inner.clone();
+
+        // the leaf level parent is not cached, but its parent is
+        assert!(matches!(
+            verifier.verify(root_hash, &merkle_proofs[2], item_hash(&42)),
+            Err(BatchProofError::CacheMismatch {
+                level: 1,
+                index: 0,
+                ..
+            })
+        ));
+        assert_eq!(verifier.cached_nodes.inner, cached_nodes);
+
+        // on an empty cache, the computed root is checked against the expected one
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        assert!(matches!(
+            verifier.verify(root_hash, &merkle_proofs[2], item_hash(&42)),
+            Err(BatchProofError::RootMismatch { .. })
+        ));
+        assert!(verifier.cached_nodes.inner.is_empty());
+    }
+
+    #[test]
+    fn test_unpinned_roots_do_not_seed_cache() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+
+        let mut forged_proof = merkle_proofs[0].clone();
+        forged_proof[1].hash = item_hash(&42);
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        let forged_root = verifier
+            .try_calculate_root_hash(&forged_proof, item_hash(&1))
+            .unwrap();
+        assert_ne!(forged_root, root_hash);
+        assert_eq!(verifier.cache_len(), 0);
+
+        // pinning the real root afterwards leaves nothing forged behind
+        verifier
+            .verify(root_hash, &merkle_proofs[0], item_hash(&1))
+            .unwrap();
+        assert_eq!(
+            verifier.try_calculate_root_hash(&merkle_proofs[1], item_hash(&2)),
+            Ok(root_hash)
+        );
+    }
+
+    #[test]
+    fn test_update_cache_rejects_forged_paths() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+
+        // a path whose leaf level sibling was tampered with
+        let mut forged_proof = merkle_proofs[4].clone();
+        forged_proof[0].hash = item_hash(&42);
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        assert!(matches!(
+            verifier.try_update_cache(root_hash, [(&forged_proof, item_hash(&5))]),
+            Err(BatchProofError::RootMismatch { .. })
+        ));
+        assert!(verifier.cached_nodes.inner.is_empty());
+
+        // a path that only carries the real siblings close to the root, paired with a bogus item
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        assert!(matches!(
+            verifier.try_update_cache(root_hash, [(&merkle_proofs[4], item_hash(&42))]),
+            Err(BatchProofError::RootMismatch { .. })
+        ));
+        assert!(verifier.cached_nodes.inner.is_empty());
+        assert!(verifier.cached_nodes.path_item_cache_mapping.is_empty());
+    }
+
+    #[test]
+    fn test_forged_paths_cannot_influence_results_with_loaded_cache() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        verifier.update_cache(
+            root_hash,
+            merkle_proofs.iter().zip(elements.iter().map(item_hash)),
+        );
+        let cached_nodes = verifier.cached_nodes.inner.clone();
+
+        // the attacker picks the leaf level sibling so that a bogus item lands on a cached node
+        let mut forged_proof = merkle_proofs[0].clone();
+        forged_proof[0].hash = item_hash(&42);
+        assert!(verifier
+            .try_update_cache(root_hash, [(&forged_proof, item_hash(&1))])
+            .is_err());
+        assert!(verifier
+            .try_calculate_root_hash(&forged_proof, item_hash(&1))
+            .is_err());
+        assert_eq!(verifier.cached_nodes.inner, cached_nodes);
+
+        // honest proofs still go through
+        for (idx, element) in elements.iter().enumerate() {
+            assert_eq!(
+                verifier.try_calculate_root_hash(&merkle_proofs[idx], item_hash(element)),
+                Ok(root_hash)
+            );
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_and_rollback() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+        let item_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        // rolling back without a checkpoint is a no-op
+        verifier
+            .verify(root_hash, &merkle_proofs[0], item_hashes[0])
+            .unwrap();
+        verifier.rollback();
+        let cached_nodes = verifier.cached_nodes.inner.clone();
+        let leaf_mapping = verifier.cached_nodes.path_item_cache_mapping.clone();
+        assert!(!cached_nodes.is_empty());
+
+        // a group where the last proof fails gets dropped as a whole
+        verifier.checkpoint();
+        verifier
+            .try_update_cache(
+                root_hash,
+                merkle_proofs[4..6]
+                    .iter()
+                    .zip(item_hashes[4..6].iter().copied()),
+            )
+            .unwrap();
+        assert!(verifier
+            .verify(root_hash, &merkle_proofs[6], item_hash(&42))
+            .is_err());
+        verifier.rollback();
+        assert_eq!(verifier.cached_nodes.inner, cached_nodes);
+        assert_eq!(verifier.cached_nodes.path_item_cache_mapping, leaf_mapping);
+
+        // a successful group is kept once a new checkpoint is taken
+        verifier
+            .verify_all(
+                root_hash,
+                merkle_proofs.iter().zip(item_hashes.iter().copied()),
+            )
+            .unwrap();
+        verifier.checkpoint();
+        let cached_nodes = verifier.cached_nodes.inner.clone();
+        verifier.rollback();
+        assert_eq!(verifier.cached_nodes.inner, cached_nodes);
+    }
+
+    #[test]
+    fn test_host_functions_hash_every_node() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+
+        let mut verifier = ProofBatchVerifier::<CountingHostFunctions>::new();
+        let _counting = count_sha256_calls();
+        verifier
+            .verify(root_hash, &merkle_proofs[0], item_hash(&1))
+            .unwrap();
+        // nothing is cached yet, so every level is hashed through the host
+        assert_eq!(sha256_calls(), 3);
+
+        // the leaf level parent is hashed, then its parent is found in the cache
+        let calls = sha256_calls();
+        verifier
+            .verify(root_hash, &merkle_proofs[2], item_hash(&3))
+            .unwrap();
+        assert_eq!(sha256_calls() - calls, 2);
+    }
+
+    #[test]
+    fn test_verify_batch_report() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+        let empty_proof: MerklePath = Vec::new();
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        let report = verifier
+            .verify_batch(
+                root_hash,
+                [
+                    (&merkle_proofs[0], item_hash(&1)),
+                    (&merkle_proofs[1], item_hash(&2)),
+                    (&merkle_proofs[5], item_hash(&42)),
+                    (&merkle_proofs[2], item_hash(&3)),
+                    (&empty_proof, item_hash(&1)),
+                ],
+            )
+            .unwrap();
+
+        assert!(!report.is_valid());
+        assert_eq!(
+            report
+                .failures()
+                .map(|(position, _)| position)
+                .collect::<Vec<_>>(),
+            [2, 4]
+        );
+
+        let proofs = &report.proofs;
+        assert_eq!(proofs[0].computed_root, Some(root_hash));
+        assert_eq!((proofs[0].hashes_computed, proofs[0].hashes_cached), (3, 0));
+        // the leaf level parent is shared with the first proof, everything above comes from the cache
+        assert_eq!((proofs[1].hashes_computed, proofs[1].hashes_cached), (1, 2));
+        // the bogus item is only caught when reaching the cached root
+        assert_eq!(proofs[2].computed_root, None);
+        assert_eq!(proofs[2].conflicting_node(), Some((0, 0)));
+        assert_eq!((proofs[2].hashes_computed, proofs[2].hashes_cached), (3, 0));
+        assert_eq!((proofs[3].hashes_computed, proofs[3].hashes_cached), (2, 1));
+        // an empty proof is only valid for a single-leaf tree
+        assert_eq!(
+            proofs[4].error,
+            Some(BatchProofError::RootMismatch {
+                expected: root_hash,
+                computed: item_hash(&1)
+            })
+        );
+        assert_eq!((proofs[4].hashes_computed, proofs[4].hashes_cached), (0, 0));
+        assert_eq!(report.hashes_computed(), 9);
+        assert_eq!(report.hashes_cached(), 3);
+    }
+
+    #[test]
+    fn test_bounded_cache_keeps_nodes_near_the_root() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+        let item_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+
+        let mut verifier =
+            ProofBatchVerifier::<MockedHostFunctions>::with_capacity(CacheCapacity::Entries(4));
+        verifier
+            .verify_all(
+                root_hash,
+                merkle_proofs.iter().zip(item_hashes.iter().copied()),
+            )
+            .unwrap();
+        // the 7 inner nodes do not fit, the deepest level gets evicted first
+        assert_eq!(verifier.cache_len(), 4);
+        let cached = &verifier.cached_nodes.inner;
+        assert!([(0, 0), (1, 0), (1, 1), (2, 3)]
+            .iter()
+            .all(|key| cached.contains_key(key)));
+
+        // eviction never affects the results
+        verifier
+            .verify_all(
+                root_hash,
+                merkle_proofs.iter().zip(item_hashes.iter().copied()),
+            )
+            .unwrap();
+        assert!(verifier
+            .verify(root_hash, &merkle_proofs[1], item_hash(&42))
+            .is_err());
+        assert_eq!(verifier.cache_len(), 4);
+
+        let bytes = verifier.cache_size_bytes();
+        let mut verifier =
+            ProofBatchVerifier::<MockedHostFunctions>::with_capacity(CacheCapacity::Bytes(bytes));
+        verifier
+            .verify_all(
+                root_hash,
+                merkle_proofs.iter().zip(item_hashes.iter().copied()),
+            )
+            .unwrap();
+        assert_eq!(verifier.cache_len(), 4);
+        assert!(verifier.cache_size_bytes() <= bytes);
+    }
+
+    #[test]
+    fn test_eviction_drops_the_leaves_of_evicted_nodes() {
+        let elements = (0..32).collect::<Vec<u32>>();
+        let (root_hash, merkle_proofs) = merklize(&elements);
+        let item_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+
+        let mut verifier =
+            ProofBatchVerifier::<MockedHostFunctions>::with_capacity(CacheCapacity::Entries(4));
+        verifier.checkpoint();
+        verifier
+            .verify_all(
+                root_hash,
+                merkle_proofs.iter().zip(item_hashes.iter().copied()),
+            )
+            .unwrap();
+        // only the cached nodes are still referenced by a leaf, and the references
+        // count towards the size of the cache
+        let referenced = verifier
+            .cached_nodes
+            .path_item_cache_mapping
+            .values()
+            .flatten()
+            .collect::<Vec<_>>();
+        assert!(referenced
+            .iter()
+            .all(|node| verifier.cached_nodes.inner.contains_key(node)));
+        assert_eq!(
+            verifier.cache_size_bytes(),
+            4 * CachedNodes::bounded_entry_size()
+                + verifier.cached_nodes.proven_siblings.len()
+                    * core::mem::size_of::<((Level, Index), CryptoHash)>()
+                + referenced.len() * CachedNodes::reference_size()
+        );
+
+        // everything committed since the checkpoint is still undone
+        verifier.rollback();
+        assert_eq!(verifier.cache_len(), 0);
+        assert!(verifier.cached_nodes.path_item_cache_mapping.is_empty());
+        assert_eq!(verifier.cache_size_bytes(), 0);
+    }
+
+    #[test]
+    fn test_forget_leaf() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+        let item_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        verifier
+            .try_update_cache(
+                root_hash,
+                merkle_proofs[..2]
+                    .iter()
+                    .zip(item_hashes[..2].iter().copied()),
+            )
+            .unwrap();
+        let cached_nodes = verifier.cached_nodes.inner.clone();
+        verifier
+            .verify(root_hash, &merkle_proofs[4], item_hashes[4])
+            .unwrap();
+
+        // only the leaf level parent of the forgotten leaf was not shared
+        verifier.forget_leaf(4, 8);
+        assert_eq!(verifier.cached_nodes.inner, cached_nodes);
+        assert!(!verifier
+            .cached_nodes
+            .path_item_cache_mapping
+            .contains_key(&(3, 4)));
+
+        // leaf 1 still goes through every inner node of leaf 0, only the hash of
+        // leaf 1 itself was given by the proof of leaf 0 alone
+        verifier.forget_leaf(0, 8);
+        assert_eq!(verifier.cache_len(), cached_nodes.len() - 1);
+        assert!(!verifier.cached_nodes.inner.contains_key(&(3, 1)));
+
+        verifier.forget_leaves(.., 8);
+        assert!(verifier.cached_nodes.inner.is_empty());
+        assert!(verifier.cached_nodes.path_item_cache_mapping.is_empty());
+
+        // forgotten nodes are computed again when needed
+        verifier
+            .verify(root_hash, &merkle_proofs[4], item_hashes[4])
+            .unwrap();
+        assert_eq!(verifier.cache_len(), 3);
+    }
+
+    #[test]
+    fn test_forget_leaf_unbalanced_tree() {
+        // leaf 4 is carried up to level 1, where it has the same index as leaf 1
+        let elements = &[1, 2, 3, 4, 5];
+        let (root_hash, merkle_proofs) = merklize(elements);
+        let item_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        for leaf_index in [1, 4] {
+            verifier
+                .verify(
+                    root_hash,
+                    &merkle_proofs[leaf_index],
+                    item_hashes[leaf_index],
+                )
+                .unwrap();
+        }
+        assert!(verifier
+            .cached_nodes
+            .path_item_cache_mapping
+            .contains_key(&(1, 1)));
+        assert!(verifier
+            .cached_nodes
+            .path_item_cache_mapping
+            .contains_key(&(3, 1)));
+
+        // the root is still referenced by leaf 4
+        verifier.forget_leaf(1, 5);
+        assert_eq!(
+            verifier.cached_nodes.inner.keys().collect::<Vec<_>>(),
+            [&(0, 0)]
+        );
+
+        verifier.forget_leaf(4, 5);
+        assert!(verifier.cached_nodes.inner.is_empty());
+        assert!(verifier.cached_nodes.path_item_cache_mapping.is_empty());
+
+        // leaves outside of the tree are ignored
+        verifier.forget_leaf(5, 5);
+        verifier.forget_leaves(3.., 5);
+    }
+
+    #[test]
+    fn test_forget_leaf_huge_tree() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+        let item_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        verifier
+            .try_update_cache(root_hash, merkle_proofs.iter().zip(item_hashes))
+            .unwrap();
+        let cached_nodes = verifier.cached_nodes.inner.clone();
+
+        // the height of such trees cannot be computed, so there is nothing to forget
+        for tree_size in [(usize::MAX >> 1) + 2, usize::MAX] {
+            verifier.forget_leaf(0, tree_size);
+            verifier.forget_leaves(.., tree_size);
+            assert_eq!(verifier.cached_nodes.inner, cached_nodes);
+        }
+
+        // in a tree of 2^62 leaves, the cached leaves sit on level 3 rather than 62
+        verifier.forget_leaves(.., 1 << 62);
+        assert_eq!(verifier.cached_nodes.inner, cached_nodes);
+
+        verifier.forget_leaves(.., 8);
+        assert!(verifier.cached_nodes.inner.is_empty());
+    }
+
+    #[test]
+    fn test_leaf_at_coordinates() {
+        for tree_size in 1..=64 {
+            for leaf_index in 0..tree_size {
+                let leaf = leaf_coordinates(leaf_index, tree_size).unwrap();
+                assert_eq!(leaf_at_coordinates(leaf, tree_size), Some(leaf_index));
+            }
+        }
+        assert_eq!(leaf_at_coordinates((3, 8), 8), None);
+        assert_eq!(leaf_at_coordinates((2, 0), 8), None);
+        assert_eq!(leaf_at_coordinates((0, 0), usize::MAX), None);
+    }
+
+    #[test]
+    fn test_verify_at_index() {
+        let elements = &[1, 2, 3, 4, 5];
+        let (root_hash, merkle_proofs) = merklize(elements);
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        for (idx, element) in elements.iter().enumerate() {
+            verifier
+                .verify_at_index(root_hash, &merkle_proofs[idx], item_hash(element), idx, 5)
+                .unwrap();
+        }
+
+        // a valid proof, claimed for another position
+        assert_eq!(
+            verifier.verify_at_index(root_hash, &merkle_proofs[1], item_hash(&2), 3, 5),
+            Err(BatchProofError::InvalidPathShape {
+                leaf_index: 3,
+                tree_size: 5
+            })
+        );
+        assert_eq!(
+            verifier.verify_at_index(root_hash, &merkle_proofs[4], item_hash(&5), 4, 8),
+            Err(BatchProofError::InvalidPathShape {
+                leaf_index: 4,
+                tree_size: 8
+            })
+        );
+        // the right position, with the wrong item
+        assert!(verifier
+            .verify_at_index(root_hash, &merkle_proofs[2], item_hash(&42), 2, 5)
+            .is_err());
+    }
+
+    #[test]
+    fn test_verify_at_index_huge_tree() {
+        let (root_hash, merkle_proofs) = merklize(&[1, 2, 3, 4, 5]);
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        assert_eq!(
+            verifier.verify_at_index(root_hash, &merkle_proofs[0], item_hash(&1), 0, usize::MAX),
+            Err(BatchProofError::TreeTooLarge {
+                tree_size: usize::MAX
+            })
+        );
+    }
+
+    #[test]
+    fn test_single_leaf_tree() {
+        let (root_hash, merkle_proofs) = merklize(&[1]);
+        assert_eq!(root_hash, item_hash(&1));
+        assert!(merkle_proofs[0].is_empty());
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        assert_eq!(
+            verifier.calculate_root_hash(&merkle_proofs[0], item_hash(&1)),
+            root_hash
+        );
+        verifier
+            .verify(root_hash, &merkle_proofs[0], item_hash(&1))
+            .unwrap();
+        verifier
+            .verify_at_index(root_hash, &merkle_proofs[0], item_hash(&1), 0, 1)
+            .unwrap();
+        assert!(verifier.cached_nodes.inner.is_empty());
+        assert!(verifier.cached_nodes.path_item_cache_mapping.is_empty());
+
+        assert_eq!(
+            verifier.verify(root_hash, &merkle_proofs[0], item_hash(&2)),
+            Err(BatchProofError::RootMismatch {
+                expected: root_hash,
+                computed: item_hash(&2)
+            })
+        );
+        // an empty proof cannot prove a leaf of a larger tree
+        assert_eq!(
+            verifier.verify_at_index(root_hash, &merkle_proofs[0], item_hash(&1), 0, 2),
+            Err(BatchProofError::EmptyProof)
+        );
+    }
 }
@@ -0,0 +1,444 @@
+//! End-to-end verification of NEAR light client execution proofs.
+//!
+//! `EXPERIMENTAL_light_client_proof` proves an execution outcome with a chain of
+//! three merkle paths: the outcome up to the outcome root of its chunk, the chunk
+//! outcome root up to the outcome root of the block, and the block up to the
+//! `block_merkle_root` of the light client head. [`LightClientVerifier`] checks the
+//! whole chain, and keeps a cache for each of those trees so that many outcomes of
+//! the same chunks, blocks and head are verified without hashing shared nodes again.
+
+use std::{collections::HashMap, vec::Vec};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::{
+    BatchProofError, BatchReport, CryptoHash, Direction, HashCounts, HostFunctions, MerklePath,
+    MultiRootVerifier, ProofBatchVerifier, ProofReport,
+};
+
+/// Part of a block header a light client gets to see, borsh-compatible with
+/// `near_primitives::block_header::BlockHeaderInnerLite`.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct BlockHeaderInnerLite {
+    pub height: u64,
+    pub epoch_id: CryptoHash,
+    pub next_epoch_id: CryptoHash,
+    pub prev_state_root: CryptoHash,
+    /// Root of the tree of the outcome roots of every chunk of the block.
+    pub outcome_root: CryptoHash,
+    /// Timestamp of the block, in nanoseconds.
+    pub timestamp: u64,
+    pub next_bp_hash: CryptoHash,
+    /// Root of the tree of the hashes of every previous block.
+    pub block_merkle_root: CryptoHash,
+}
+
+/// Header of a block, as found in light client proofs.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct LightClientBlockLite {
+    pub prev_block_hash: CryptoHash,
+    pub inner_rest_hash: CryptoHash,
+    pub inner_lite: BlockHeaderInnerLite,
+}
+
+impl LightClientBlockLite {
+    /// Hash of the block, computed the way NEAR does out of the header parts.
+    pub fn hash<HF: HostFunctions>(&self) -> CryptoHash {
+        let inner_lite_hash = CryptoHash::hash_borsh::<HF, _>(&self.inner_lite);
+        let inner_hash = CryptoHash::hash_pair::<HF>(&inner_lite_hash, &self.inner_rest_hash);
+        CryptoHash::hash_pair::<HF>(&inner_hash, &self.prev_block_hash)
+    }
+}
+
+/// Proof that an execution outcome happened in a block known to the light client head.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct LightClientExecutionProof {
+    /// Hashes the outcome commits to: its id, then the hashes of the outcome itself.
+    pub outcome_hashes: Vec<CryptoHash>,
+    /// Path from the outcome to the outcome root of its chunk.
+    pub outcome_proof: MerklePath,
+    /// Path from the chunk outcome root to the outcome root of the block.
+    pub outcome_root_proof: MerklePath,
+    /// Header of the block the outcome is part of.
+    pub block_header_lite: LightClientBlockLite,
+    /// Path from the block hash to the block merkle root of the head.
+    pub block_proof: MerklePath,
+}
+
+impl LightClientExecutionProof {
+    /// Leaf of the outcome in the outcome tree of its chunk.
+    pub fn outcome_hash<HF: HostFunctions>(&self) -> CryptoHash {
+        CryptoHash::hash_borsh::<HF, _>(&self.outcome_hashes)
+    }
+}
+
+/// Verifies [`LightClientExecutionProof`]s against the `block_merkle_root` of a
+/// trusted light client head.
+///
+/// Every cached node is proven up to the head: the block is checked first, and the
+/// outcome root of a chunk is proven in its block before any of its outcome paths
+/// gets cached.
+#[derive(Debug)]
+pub struct LightClientVerifier<HF: HostFunctions> {
+    /// `block_merkle_root` of the head
+    head: CryptoHash,
+    /// paths of blocks, pinned to the head
+    blocks: ProofBatchVerifier<HF>,
+    /// paths of chunk outcome roots, per block hash
+    outcome_roots: MultiRootVerifier<HF, CryptoHash>,
+    /// paths of outcomes, pinned to the outcome root of their chunk, per block hash
+    /// and directions of the path of the chunk in the block
+    chunks: HashMap<(CryptoHash, Vec<Direction>), ProofBatchVerifier<HF>>,
+}
+
+impl<HF: HostFunctions> LightClientVerifier<HF> {
+    /// Creates a verifier trusting a head with the given `block_merkle_root`.
+    pub fn new(head_block_merkle_root: CryptoHash) -> Self {
+        let mut blocks = ProofBatchVerifier::new();
+        blocks.trusted_root = Some(head_block_merkle_root);
+        Self {
+            head: head_block_merkle_root,
+            blocks,
+            outcome_roots: MultiRootVerifier::new(),
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// `block_merkle_root` of the trusted head.
+    pub fn head(&self) -> CryptoHash {
+        self.head
+    }
+
+    /// Moves to a new head. Paths of blocks are cached against the head, so that
+    /// cache starts over; outcomes of blocks verified so far stay cached.
+    pub fn set_head(&mut self, head_block_merkle_root: CryptoHash) {
+        if head_block_merkle_root != self.head {
+            *self = Self {
+                outcome_roots: core::mem::take(&mut self.outcome_roots),
+                chunks: core::mem::take(&mut self.chunks),
+                ..Self::new(head_block_merkle_root)
+            };
+        }
+    }
+
+    /// Verifies the whole chain of `proof`, from the outcome up to the head.
+    pub fn verify(&mut self, proof: &LightClientExecutionProof) -> Result<(), BatchProofError> {
+        self.commit(proof, &mut HashCounts::default()).map(|_| ())
+    }
+
+    /// Same as [`Self::verify`], keeping track of the hashes needed over the three
+    /// trees of the chain. Returns the `block_merkle_root` the block lands on.
+    fn commit(
+        &mut self,
+        proof: &LightClientExecutionProof,
+        counts: &mut HashCounts,
+    ) -> Result<CryptoHash, BatchProofError> {
+        let block_hash = proof.block_header_lite.hash::<HF>();
+        let head = self
+            .blocks
+            .commit_root_hash(&proof.block_proof, block_hash, counts)?;
+
+        let outcome_root = proof.block_header_lite.inner_lite.outcome_root;
+        let outcome_hash = proof.outcome_hash::<HF>();
+        let chunk = (
+            block_hash,
+            proof
+                .outcome_root_proof
+                .iter()
+                .map(|item| item.direction)
+                .collect::<Vec<_>>(),
+        );
+        match self.chunks.get_mut(&chunk) {
+            Some(verifier) => {
+                let chunk_root = verifier
+                    .trusted_root()
+                    .expect("chunk verifiers are pinned once their root is proven");
+                verifier.commit_root_hash(&proof.outcome_proof, outcome_hash, counts)?;
+                self.verify_chunk_root(block_hash, outcome_root, proof, chunk_root, counts)?;
+            }
+            None => {
+                // the chunk root is only known once the path is hashed, and only
+                // trusted once it is proven in the block
+                let mut verifier = ProofBatchVerifier::new();
+                let path =
+                    verifier.compute_root_hash(&proof.outcome_proof, outcome_hash, counts)?;
+                self.verify_chunk_root(block_hash, outcome_root, proof, path.root_hash, counts)?;
+                verifier.trusted_root = Some(path.root_hash);
+                verifier
+                    .cached_nodes
+                    .commit(path.computed_nodes, &path.used_nodes, path.leaf);
+                verifier.cached_nodes.prove_siblings(&path.sibling_nodes);
+                self.chunks.insert(chunk, verifier);
+            }
+        }
+        Ok(head)
+    }
+
+    /// Verifies that `chunk_root` is part of the outcome root of the block.
+    fn verify_chunk_root(
+        &mut self,
+        block_hash: CryptoHash,
+        outcome_root: CryptoHash,
+        proof: &LightClientExecutionProof,
+        chunk_root: CryptoHash,
+        counts: &mut HashCounts,
+    ) -> Result<(), BatchProofError> {
+        self.outcome_roots
+            .verifier(block_hash, outcome_root)
+            .commit_root_hash(
+                &proof.outcome_root_proof,
+                CryptoHash::hash_borsh::<HF, _>(&chunk_root),
+                counts,
+            )
+            .map(|_| ())
+    }
+
+    /// Verifies a batch of proofs, stopping at the first one that fails.
+    pub fn verify_all<'a>(
+        &mut self,
+        proofs: impl IntoIterator<Item = &'a LightClientExecutionProof>,
+    ) -> Result<(), BatchProofError> {
+        proofs.into_iter().try_for_each(|proof| self.verify(proof))
+    }
+
+    /// Verifies every proof of a batch, without stopping at the first failure. The
+    /// hashes of each proof are counted over the three trees of its chain.
+    pub fn verify_batch<'a>(
+        &mut self,
+        proofs: impl IntoIterator<Item = &'a LightClientExecutionProof>,
+    ) -> BatchReport {
+        let proofs = proofs
+            .into_iter()
+            .map(|proof| {
+                let mut counts = HashCounts::default();
+                let result = self.commit(proof, &mut counts);
+                ProofReport::new(result, counts.computed, counts.cached)
+            })
+            .collect();
+        BatchReport { proofs }
+    }
+
+    /// Only keeps the outcome caches of the blocks for which `keep` returns true,
+    /// e.g. to drop old blocks.
+    pub fn retain_blocks(&mut self, mut keep: impl FnMut(&CryptoHash) -> bool) {
+        self.outcome_roots.retain(|block_hash, _| keep(block_hash));
+        self.chunks.retain(|(block_hash, _), _| keep(block_hash));
+    }
+
+    /// Number of nodes currently cached, over every tree.
+    pub fn cache_len(&self) -> usize {
+        self.blocks.cache_len()
+            + self.outcome_roots.cache_len()
+            + self
+                .chunks
+                .values()
+                .map(ProofBatchVerifier::cache_len)
+                .sum::<usize>()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use near_primitives::{
+        block_header::BlockHeader, hash::CryptoHash as NearCryptoHash, utils::from_timestamp,
+        version::PROTOCOL_VERSION, views::LightClientBlockLiteView,
+    };
+
+    use super::*;
+    use crate::{test_utils::MockedHostFunctions, MerkleTree};
+
+    type Tree = MerkleTree<MockedHostFunctions>;
+
+    /// Blocks made of chunks of outcomes, all included in a single head.
+    struct Chain {
+        head: CryptoHash,
+        proofs: Vec<LightClientExecutionProof>,
+    }
+
+    fn chain(blocks: u64, chunks: u64, outcomes: u64) -> Chain {
+        let mut proofs = Vec::new();
+        let mut headers = Vec::new();
+        for height in 0..blocks {
+            let outcome_hashes = (0..chunks)
+                .map(|chunk| {
+                    (0..outcomes)
+                        .map(|outcome| {
+                            let id = (height, chunk, outcome).try_to_vec().unwrap();
+                            vec![
+                                CryptoHash::hash_bytes::<MockedHostFunctions>(&id),
+                                CryptoHash([outcome as u8; 32]),
+                            ]
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let chunk_trees = outcome_hashes
+                .iter()
+                .map(|outcomes| Tree::from_items(outcomes))
+                .collect::<Vec<_>>();
+            let chunk_roots = chunk_trees.iter().map(Tree::root).collect::<Vec<_>>();
+            let block_tree = Tree::from_items(&chunk_roots);
+            headers.push(LightClientBlockLite {
+                prev_block_hash: CryptoHash([height as u8; 32]),
+                inner_rest_hash: CryptoHash([7; 32]),
+                inner_lite: BlockHeaderInnerLite {
+                    height,
+                    epoch_id: CryptoHash([1; 32]),
+                    next_epoch_id: CryptoHash([2; 32]),
+                    prev_state_root: CryptoHash([3; 32]),
+                    outcome_root: block_tree.root(),
+                    timestamp: height * 1_000_000_000,
+                    next_bp_hash: CryptoHash([4; 32]),
+                    block_merkle_root: CryptoHash([5; 32]),
+                },
+            });
+            for (chunk, chunk_tree) in chunk_trees.iter().enumerate() {
+                for (outcome, outcome_hashes) in outcome_hashes[chunk].iter().enumerate() {
+                    proofs.push(LightClientExecutionProof {
+                        outcome_hashes: outcome_hashes.clone(),
+                        outcome_proof: chunk_tree.path(outcome).unwrap(),
+                        outcome_root_proof: block_tree.path(chunk).unwrap(),
+                        block_header_lite: headers[height as usize].clone(),
+                        block_proof: Vec::new(),
+                    });
+                }
+            }
+        }
+
+        let block_tree = Tree::from_leaf_hashes(
+            headers
+                .iter()
+                .map(LightClientBlockLite::hash::<MockedHostFunctions>)
+                .collect(),
+        );
+        for proof in proofs.iter_mut() {
+            proof.block_proof = block_tree
+                .path(proof.block_header_lite.inner_lite.height as usize)
+                .unwrap();
+        }
+        Chain {
+            head: block_tree.root(),
+            proofs,
+        }
+    }
+
+    #[test]
+    fn test_block_hash_matches_near() {
+        let header = BlockHeader::genesis(
+            PROTOCOL_VERSION,
+            42,
+            NearCryptoHash([1; 32]),
+            NearCryptoHash([2; 32]),
+            NearCryptoHash([3; 32]),
+            NearCryptoHash([4; 32]),
+            4,
+            NearCryptoHash([5; 32]),
+            from_timestamp(1_600_000_000_000_000_000),
+            100,
+            1_000_000,
+            NearCryptoHash([6; 32]),
+        );
+        let view = LightClientBlockLiteView::from(header.clone());
+        let block = LightClientBlockLite {
+            prev_block_hash: CryptoHash(view.prev_block_hash.0),
+            inner_rest_hash: CryptoHash(view.inner_rest_hash.0),
+            inner_lite: BlockHeaderInnerLite {
+                height: view.inner_lite.height,
+                epoch_id: CryptoHash(view.inner_lite.epoch_id.0),
+                next_epoch_id: CryptoHash(view.inner_lite.next_epoch_id.0),
+                prev_state_root: CryptoHash(view.inner_lite.prev_state_root.0),
+                outcome_root: CryptoHash(view.inner_lite.outcome_root.0),
+                timestamp: view.inner_lite.timestamp_nanosec,
+                next_bp_hash: CryptoHash(view.inner_lite.next_bp_hash.0),
+                block_merkle_root: CryptoHash(view.inner_lite.block_merkle_root.0),
+            },
+        };
+        assert_eq!(
+            block.inner_lite.try_to_vec().unwrap(),
+            header.inner_lite_bytes()
+        );
+        assert_eq!(
+            block.hash::<MockedHostFunctions>(),
+            CryptoHash(header.hash().0)
+        );
+    }
+
+    #[test]
+    fn test_verify_execution_proofs() {
+        let Chain { head, proofs } = chain(5, 3, 4);
+
+        let mut verifier = LightClientVerifier::<MockedHostFunctions>::new(head);
+        let report = verifier.verify_batch(&proofs);
+        assert!(report.is_valid());
+        assert!(report.hashes_computed() > 0);
+        let cache_len = verifier.cache_len();
+        // proofs verified again only hash the parent of their leaf in each of the
+        // three trees, the cache serves the rest
+        let report = verifier.verify_batch(&proofs);
+        assert!(report.is_valid());
+        assert!(report
+            .proofs
+            .iter()
+            .all(|report| report.hashes_computed == 3
+                && report.hashes_cached > 0
+                && report.computed_root == Some(head)));
+        assert_eq!(verifier.cache_len(), cache_len);
+
+        let mut forged = proofs[7].clone();
+        forged.outcome_hashes[1] = CryptoHash::default();
+        assert!(verifier.verify(&forged).is_err());
+
+        // an outcome of an unknown chunk must be proven in the block
+        let mut forged = proofs[7].clone();
+        forged.outcome_root_proof[0].direction = Direction::Left;
+        forged.outcome_proof.clear();
+        assert!(verifier.verify(&forged).is_err());
+
+        let mut forged = proofs[7].clone();
+        forged.block_header_lite.inner_lite.timestamp += 1;
+        assert!(matches!(
+            verifier.verify(&forged),
+            Err(BatchProofError::CacheMismatch { .. }) | Err(BatchProofError::RootMismatch { .. })
+        ));
+        assert_eq!(verifier.cache_len(), cache_len);
+
+        // a proof of a block the head does not know of
+        let other = chain(6, 3, 4);
+        let mut fresh = LightClientVerifier::<MockedHostFunctions>::new(other.head);
+        let report = fresh.verify_batch(proofs.iter().rev().take(3).chain(&other.proofs[..3]));
+        assert_eq!(
+            report
+                .failures()
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert!(report.proofs[3..]
+            .iter()
+            .all(|report| report.computed_root == Some(other.head)));
+    }
+
+    #[test]
+    fn test_head_and_block_caches() {
+        let Chain { head, proofs } = chain(4, 2, 2);
+
+        let mut verifier = LightClientVerifier::<MockedHostFunctions>::new(head);
+        verifier.verify_all(&proofs).unwrap();
+
+        // outcomes stay cached when the head moves, blocks do not
+        let cache_len = verifier.cache_len();
+        verifier.set_head(CryptoHash::default());
+        assert_eq!(verifier.head(), CryptoHash::default());
+        assert!(verifier.cache_len() < cache_len);
+        assert!(verifier.verify(&proofs[0]).is_err());
+        verifier.set_head(head);
+        verifier.verify_all(&proofs).unwrap();
+        assert_eq!(verifier.cache_len(), cache_len);
+
+        let first_block = proofs[0].block_header_lite.hash::<MockedHostFunctions>();
+        verifier.retain_blocks(|block_hash| *block_hash != first_block);
+        assert!(verifier.cache_len() < cache_len);
+        verifier.verify_all(&proofs).unwrap();
+        assert_eq!(verifier.cache_len(), cache_len);
+    }
+}
@@ -0,0 +1,521 @@
+//! Verification of NEAR light client headers.
+//!
+//! Every root the other verifiers of this crate trust comes from a block header. A
+//! light client moves its head forward with the headers of `next_light_client_block`,
+//! which carry the approvals of the block producers of their epoch: ed25519
+//! signatures endorsing the block after the header, that must come from more than
+//! two thirds of the stake. [`LightClientHeaderVerifier`] checks them through
+//! [`HostFunctions::ed25519_verify`], and learns the block producers of the next
+//! epoch from the headers that commit to them through `next_bp_hash`.
+
+use core::marker::PhantomData;
+use std::{collections::HashMap, string::String, vec::Vec};
+
+use borsh::{
+    maybestd::io::{Error, ErrorKind, Result as IoResult, Write},
+    BorshDeserialize, BorshSerialize,
+};
+
+use crate::{
+    light_client::{BlockHeaderInnerLite, LightClientBlockLite},
+    BatchProofError, CryptoHash, HostFunctions,
+};
+
+/// Version tag of a [`ValidatorStake`] in its borsh serialization.
+const VALIDATOR_STAKE_V1: u8 = 0;
+
+/// Tag of an endorsement in the borsh serialization of `ApprovalInner`.
+const ENDORSEMENT: u8 = 0;
+
+/// Public key of a validator, borsh-compatible with `near_crypto::PublicKey`.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum PublicKey {
+    Ed25519([u8; 32]),
+    Secp256k1([u8; 64]),
+}
+
+/// Signature of an approval, borsh-compatible with `near_crypto::Signature`.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum Signature {
+    Ed25519([u8; 64]),
+    Secp256k1([u8; 65]),
+}
+
+/// Block producer of an epoch, borsh-compatible with the `V1` variant of
+/// `near_primitives::views::validator_stake_view::ValidatorStakeView`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidatorStake {
+    pub account_id: String,
+    pub public_key: PublicKey,
+    pub stake: u128,
+}
+
+impl BorshSerialize for ValidatorStake {
+    fn serialize<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        VALIDATOR_STAKE_V1.serialize(writer)?;
+        self.account_id.serialize(writer)?;
+        self.public_key.serialize(writer)?;
+        self.stake.serialize(writer)
+    }
+}
+
+impl BorshDeserialize for ValidatorStake {
+    fn deserialize(buf: &mut &[u8]) -> IoResult<Self> {
+        match u8::deserialize(buf)? {
+            VALIDATOR_STAKE_V1 => Ok(ValidatorStake {
+                account_id: String::deserialize(buf)?,
+                public_key: PublicKey::deserialize(buf)?,
+                stake: u128::deserialize(buf)?,
+            }),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "unknown validator stake version",
+            )),
+        }
+    }
+}
+
+/// Header returned by `next_light_client_block`, with the fields of
+/// `near_primitives::views::LightClientBlockView`.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct LightClientBlock {
+    pub prev_block_hash: CryptoHash,
+    /// Hash of the inner parts of the block after this one.
+    pub next_block_inner_hash: CryptoHash,
+    pub inner_lite: BlockHeaderInnerLite,
+    pub inner_rest_hash: CryptoHash,
+    /// Block producers of the next epoch, hashing to `inner_lite.next_bp_hash`.
+    pub next_bps: Option<Vec<ValidatorStake>>,
+    /// Approvals of the block after this one, in the order of the block producers
+    /// of the epoch.
+    pub approvals_after_next: Vec<Option<Signature>>,
+}
+
+impl LightClientBlock {
+    /// The header as found in light client proofs.
+    pub fn lite(&self) -> LightClientBlockLite {
+        LightClientBlockLite {
+            prev_block_hash: self.prev_block_hash,
+            inner_rest_hash: self.inner_rest_hash,
+            inner_lite: self.inner_lite.clone(),
+        }
+    }
+
+    /// Hash of the block.
+    pub fn hash<HF: HostFunctions>(&self) -> CryptoHash {
+        self.lite().hash::<HF>()
+    }
+
+    /// Hash of the block after this one, which the approvals endorse.
+    pub fn next_block_hash<HF: HostFunctions>(&self) -> CryptoHash {
+        CryptoHash::hash_pair::<HF>(&self.next_block_inner_hash, &self.hash::<HF>())
+    }
+
+    /// Message signed by the approvals: the endorsement of the next block, followed
+    /// by the height of the block after it.
+    ///
+    /// Fails if that height does not fit a `u64`.
+    pub fn approval_message<HF: HostFunctions>(&self) -> Result<Vec<u8>, BatchProofError> {
+        let height = self.inner_lite.height;
+        let target_height = height
+            .checked_add(2)
+            .ok_or(BatchProofError::HeightOutOfRange { height })?;
+        let mut message = Vec::with_capacity(1 + 32 + 8);
+        message.push(ENDORSEMENT);
+        message.extend_from_slice(&self.next_block_hash::<HF>().0);
+        message.extend_from_slice(&target_height.to_le_bytes());
+        Ok(message)
+    }
+}
+
+/// Moves a trusted light client head forward, one approved header at a time.
+///
+/// The roots of the head, like its `block_merkle_root` for a
+/// [`crate::LightClientVerifier`], can then be trusted by the proof verifiers.
+#[derive(Debug)]
+pub struct LightClientHeaderVerifier<HF: HostFunctions> {
+    head: LightClientBlockLite,
+    /// block producers of the epoch of the head, and of the next one once known
+    block_producers: HashMap<CryptoHash, Vec<ValidatorStake>>,
+    _hf: PhantomData<HF>,
+}
+
+impl<HF: HostFunctions> LightClientHeaderVerifier<HF> {
+    /// Creates a verifier trusting `head`, whose epoch has the given block producers.
+    pub fn new(head: LightClientBlockLite, block_producers: Vec<ValidatorStake>) -> Self {
+        let mut epochs = HashMap::new();
+        epochs.insert(head.inner_lite.epoch_id, block_producers);
+        Self {
+            head,
+            block_producers: epochs,
+            _hf: PhantomData,
+        }
+    }
+
+    /// The trusted head.
+    pub fn head(&self) -> &LightClientBlockLite {
+        &self.head
+    }
+
+    /// Block producers of an epoch, if it is the epoch of the head or the next one
+    /// and they are known.
+    pub fn block_producers(&self, epoch_id: &CryptoHash) -> Option<&[ValidatorStake]> {
+        self.block_producers.get(epoch_id).map(Vec::as_slice)
+    }
+
+    /// Verifies that `block` extends the head and is approved by more than two
+    /// thirds of the stake of its epoch, then moves the head to it.
+    ///
+    /// The head is left as is when the header is rejected.
+    pub fn verify(&mut self, block: &LightClientBlock) -> Result<(), BatchProofError> {
+        let inner_lite = &block.inner_lite;
+        let head = &self.head.inner_lite;
+        if inner_lite.height <= head.height {
+            return Err(BatchProofError::StaleHeader {
+                height: inner_lite.height,
+                head_height: head.height,
+            });
+        }
+        if inner_lite.epoch_id != head.epoch_id && inner_lite.epoch_id != head.next_epoch_id {
+            return Err(BatchProofError::UnknownEpoch {
+                epoch_id: inner_lite.epoch_id,
+            });
+        }
+        // the producers of the epoch after the next must be known before moving on
+        if inner_lite.epoch_id == head.next_epoch_id && block.next_bps.is_none() {
+            return Err(BatchProofError::MissingNextBlockProducers {
+                epoch_id: inner_lite.epoch_id,
+            });
+        }
+        let block_producers = self.block_producers.get(&inner_lite.epoch_id).ok_or(
+            BatchProofError::UnknownEpoch {
+                epoch_id: inner_lite.epoch_id,
+            },
+        )?;
+        self.check_approvals(block, block_producers)?;
+
+        if let Some(next_bps) = &block.next_bps {
+            let computed = CryptoHash::hash_borsh::<HF, _>(next_bps);
+            if computed != inner_lite.next_bp_hash {
+                return Err(BatchProofError::NextBlockProducersMismatch {
+                    expected: inner_lite.next_bp_hash,
+                    computed,
+                });
+            }
+        }
+
+        self.head = block.lite();
+        if let Some(next_bps) = &block.next_bps {
+            self.block_producers
+                .insert(inner_lite.next_epoch_id, next_bps.clone());
+        }
+        let (epoch_id, next_epoch_id) = (inner_lite.epoch_id, inner_lite.next_epoch_id);
+        self.block_producers
+            .retain(|epoch, _| *epoch == epoch_id || *epoch == next_epoch_id);
+        Ok(())
+    }
+
+    /// Verifies a sequence of headers, stopping at the first one that fails.
+    pub fn verify_all<'a>(
+        &mut self,
+        blocks: impl IntoIterator<Item = &'a LightClientBlock>,
+    ) -> Result<(), BatchProofError> {
+        blocks.into_iter().try_for_each(|block| self.verify(block))
+    }
+
+    fn check_approvals(
+        &self,
+        block: &LightClientBlock,
+        block_producers: &[ValidatorStake],
+    ) -> Result<(), BatchProofError> {
+        let message = block.approval_message::<HF>()?;
+        let total = block_producers
+            .iter()
+            .fold(0u128, |total, bp| total.saturating_add(bp.stake));
+        let mut approved = 0u128;
+        for (index, (approval, bp)) in block
+            .approvals_after_next
+            .iter()
+            .zip(block_producers)
+            .enumerate()
+        {
+            match (approval, &bp.public_key) {
+                (None, _) => {}
+                (Some(Signature::Ed25519(signature)), PublicKey::Ed25519(public_key)) => {
+                    if !HF::ed25519_verify(signature, &message, public_key) {
+                        return Err(BatchProofError::InvalidApproval { index });
+                    }
+                    approved = approved.saturating_add(bp.stake);
+                }
+                // the host only checks ed25519, so these approvals do not count
+                (Some(Signature::Secp256k1(_)), PublicKey::Secp256k1(_)) => {}
+                (Some(_), _) => return Err(BatchProofError::InvalidApproval { index }),
+            }
+        }
+        if approved <= two_thirds(total) {
+            return Err(BatchProofError::InsufficientApprovals { approved, total });
+        }
+        Ok(())
+    }
+}
+
+/// `stake * 2 / 3`, rounded down, without overflowing.
+fn two_thirds(stake: u128) -> u128 {
+    stake / 3 * 2 + stake % 3 * 2 / 3
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use near_crypto::{KeyType, PublicKey as NearPublicKey, SecretKey, Signature as NearSignature};
+    use near_primitives::{
+        block_header::{Approval, ApprovalInner, BlockHeader},
+        hash::CryptoHash as NearCryptoHash,
+        views::validator_stake_view::{ValidatorStakeView, ValidatorStakeViewV1},
+    };
+
+    use super::*;
+    use crate::{test_utils::MockedHostFunctions, LightClientVerifier};
+
+    type Verifier = LightClientHeaderVerifier<MockedHostFunctions>;
+
+    /// Host without signature verification.
+    struct HashOnlyHostFunctions;
+    impl HostFunctions for HashOnlyHostFunctions {
+        fn sha256(data: &[u8]) -> [u8; 32] {
+            MockedHostFunctions::sha256(data)
+        }
+    }
+
+    fn epoch_id(epoch: u8) -> CryptoHash {
+        CryptoHash([epoch; 32])
+    }
+
+    fn public_key(secret_key: &SecretKey) -> PublicKey {
+        match secret_key.public_key() {
+            NearPublicKey::ED25519(public_key) => PublicKey::Ed25519(public_key.0),
+            NearPublicKey::SECP256K1(_) => unreachable!(),
+        }
+    }
+
+    fn sign(secret_key: &SecretKey, message: &[u8]) -> Signature {
+        match secret_key.sign(message) {
+            NearSignature::ED25519(signature) => Signature::Ed25519(signature.to_bytes()),
+            NearSignature::SECP256K1(_) => unreachable!(),
+        }
+    }
+
+    /// Keys and stakes of the block producers of an epoch.
+    fn block_producers(epoch: u8, stakes: &[u128]) -> (Vec<SecretKey>, Vec<ValidatorStake>) {
+        stakes
+            .iter()
+            .enumerate()
+            .map(|(index, stake)| {
+                let secret_key =
+                    SecretKey::from_seed(KeyType::ED25519, &format!("bp{}.{}", index, epoch));
+                let bp = ValidatorStake {
+                    account_id: format!("bp{}.near", index),
+                    public_key: public_key(&secret_key),
+                    stake: *stake,
+                };
+                (secret_key, bp)
+            })
+            .unzip()
+    }
+
+    fn inner_lite(height: u64, epoch: u8, next_bp_hash: CryptoHash) -> BlockHeaderInnerLite {
+        BlockHeaderInnerLite {
+            height,
+            epoch_id: epoch_id(epoch),
+            next_epoch_id: epoch_id(epoch + 1),
+            prev_state_root: CryptoHash([3; 32]),
+            outcome_root: CryptoHash([4; 32]),
+            timestamp: height * 1_000_000_000,
+            next_bp_hash,
+            block_merkle_root: CryptoHash([height as u8; 32]),
+        }
+    }
+
+    fn head() -> LightClientBlockLite {
+        LightClientBlockLite {
+            prev_block_hash: CryptoHash([1; 32]),
+            inner_rest_hash: CryptoHash([2; 32]),
+            inner_lite: inner_lite(10, 1, CryptoHash::default()),
+        }
+    }
+
+    /// A header of `epoch`, approved by the keys for which `approve` is set.
+    fn block(
+        height: u64,
+        epoch: u8,
+        next_bps: Option<Vec<ValidatorStake>>,
+        keys: &[SecretKey],
+        approve: &[bool],
+    ) -> LightClientBlock {
+        let next_bp_hash = next_bps
+            .as_ref()
+            .map(CryptoHash::hash_borsh::<MockedHostFunctions, _>)
+            .unwrap_or_default();
+        let mut block = LightClientBlock {
+            prev_block_hash: CryptoHash([height as u8 - 1; 32]),
+            next_block_inner_hash: CryptoHash([9; 32]),
+            inner_lite: inner_lite(height, epoch, next_bp_hash),
+            inner_rest_hash: CryptoHash([7; 32]),
+            next_bps,
+            approvals_after_next: Vec::new(),
+        };
+        let message = block.approval_message::<MockedHostFunctions>().unwrap();
+        block.approvals_after_next = keys
+            .iter()
+            .zip(approve)
+            .map(|(key, approve)| approve.then(|| sign(key, &message)))
+            .collect();
+        block
+    }
+
+    #[test]
+    fn test_encodings_match_near() {
+        let (keys, bps) = block_producers(1, &[10]);
+        let block = block(11, 1, Some(bps.clone()), &keys, &[true]);
+
+        let view = ValidatorStakeView::V1(ValidatorStakeViewV1 {
+            account_id: "bp0.near".parse().unwrap(),
+            public_key: keys[0].public_key(),
+            stake: 10,
+        });
+        assert_eq!(bps[0].try_to_vec().unwrap(), view.try_to_vec().unwrap());
+        assert_eq!(
+            ValidatorStake::try_from_slice(&view.try_to_vec().unwrap()).unwrap(),
+            bps[0]
+        );
+        let message = block.approval_message::<MockedHostFunctions>().unwrap();
+        assert_eq!(
+            block.approvals_after_next[0].try_to_vec().unwrap(),
+            Some(keys[0].sign(&message)).try_to_vec().unwrap()
+        );
+
+        // the next block commits to this one as its previous block
+        let (next_inner_lite, next_inner_rest) = ([5u8; 10], [6u8; 20]);
+        let mut block = block;
+        block.next_block_inner_hash = CryptoHash::hash_pair::<MockedHostFunctions>(
+            &CryptoHash::hash_bytes::<MockedHostFunctions>(&next_inner_lite),
+            &CryptoHash::hash_bytes::<MockedHostFunctions>(&next_inner_rest),
+        );
+        let next_block_hash = BlockHeader::compute_hash(
+            NearCryptoHash(block.hash::<MockedHostFunctions>().0),
+            &next_inner_lite,
+            &next_inner_rest,
+        );
+        assert_eq!(
+            block.next_block_hash::<MockedHostFunctions>(),
+            CryptoHash(next_block_hash.0)
+        );
+        assert_eq!(
+            block.approval_message::<MockedHostFunctions>().unwrap(),
+            Approval::get_data_for_sig(&ApprovalInner::Endorsement(next_block_hash), 13)
+        );
+    }
+
+    #[test]
+    fn test_verify_headers_across_epochs() {
+        let (keys1, bps1) = block_producers(1, &[10, 20, 30]);
+        let (keys2, bps2) = block_producers(2, &[40, 50]);
+        let (_, bps3) = block_producers(3, &[60]);
+
+        let mut verifier = Verifier::new(head(), bps1);
+        // 50 out of 60
+        let first = block(11, 1, Some(bps2.clone()), &keys1, &[false, true, true]);
+        verifier.verify(&first).unwrap();
+        assert_eq!(verifier.head(), &first.lite());
+        assert_eq!(verifier.block_producers(&epoch_id(2)), Some(&bps2[..]));
+
+        let next = block(15, 2, Some(bps3), &keys2, &[true, true]);
+        verifier.verify_all([&next]).unwrap();
+        assert_eq!(verifier.head().inner_lite.epoch_id, epoch_id(2));
+        assert_eq!(verifier.block_producers(&epoch_id(1)), None);
+        assert!(verifier.block_producers(&epoch_id(3)).is_some());
+
+        // roots of the verified head are the ones proofs are checked against
+        let light_client = LightClientVerifier::<MockedHostFunctions>::new(
+            verifier.head().inner_lite.block_merkle_root,
+        );
+        assert_eq!(light_client.head(), next.inner_lite.block_merkle_root);
+    }
+
+    #[test]
+    fn test_reject_unapproved_headers() {
+        let (keys1, bps1) = block_producers(1, &[10, 20, 30]);
+        let (keys2, bps2) = block_producers(2, &[40, 50]);
+        let mut verifier = Verifier::new(head(), bps1.clone());
+
+        // exactly two thirds of the stake is not enough
+        assert_eq!(
+            verifier.verify(&block(11, 1, None, &keys1, &[true, false, true])),
+            Err(BatchProofError::InsufficientApprovals {
+                approved: 40,
+                total: 60
+            })
+        );
+        // approvals of another block
+        let mut forged = block(11, 1, None, &keys1, &[true; 3]);
+        forged.next_block_inner_hash = CryptoHash::default();
+        assert_eq!(
+            verifier.verify(&forged),
+            Err(BatchProofError::InvalidApproval { index: 0 })
+        );
+        // approvals by the producers of another epoch
+        assert_eq!(
+            verifier.verify(&block(11, 1, None, &keys2, &[false, true])),
+            Err(BatchProofError::InvalidApproval { index: 1 })
+        );
+        assert_eq!(
+            verifier.verify(&block(10, 1, None, &keys1, &[true; 3])),
+            Err(BatchProofError::StaleHeader {
+                height: 10,
+                head_height: 10
+            })
+        );
+        // no block can be approved two heights above the last one
+        for height in [u64::MAX - 1, u64::MAX] {
+            let mut block = block(11, 1, None, &keys1, &[true; 3]);
+            block.inner_lite.height = height;
+            assert_eq!(
+                verifier.verify(&block),
+                Err(BatchProofError::HeightOutOfRange { height })
+            );
+        }
+        assert_eq!(
+            verifier.verify(&block(11, 3, None, &keys1, &[true; 3])),
+            Err(BatchProofError::UnknownEpoch {
+                epoch_id: epoch_id(3)
+            })
+        );
+        assert_eq!(
+            verifier.verify(&block(11, 2, None, &keys2, &[true; 2])),
+            Err(BatchProofError::MissingNextBlockProducers {
+                epoch_id: epoch_id(2)
+            })
+        );
+        // the producers of the next epoch are only known from a verified header
+        assert_eq!(
+            verifier.verify(&block(11, 2, Some(bps2.clone()), &keys2, &[true; 2])),
+            Err(BatchProofError::UnknownEpoch {
+                epoch_id: epoch_id(2)
+            })
+        );
+        let mut forged = block(11, 1, Some(bps2), &keys1, &[true; 3]);
+        forged.next_bps = Some(bps1.clone());
+        assert!(matches!(
+            verifier.verify(&forged),
+            Err(BatchProofError::NextBlockProducersMismatch { .. })
+        ));
+        assert_eq!(verifier.head(), &head());
+        assert_eq!(verifier.block_producers(&epoch_id(2)), None);
+
+        // hosts without signature verification trust no header
+        let mut verifier = LightClientHeaderVerifier::<HashOnlyHostFunctions>::new(head(), bps1);
+        assert_eq!(
+            verifier.verify(&block(11, 1, None, &keys1, &[true; 3])),
+            Err(BatchProofError::InvalidApproval { index: 0 })
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Verifier for proofs of several trees, e.g. every shard of several blocks.
+//!
+//! A [`ProofBatchVerifier`] caches nodes by their position only, so it must only
+//! see proofs of one tree. [`MultiRootVerifier`] keeps one of them per namespace
+//! and trusted root, and routes every proof to the right one.
+
+use core::hash::Hash;
+use std::{collections::HashMap, vec::Vec};
+
+use crate::{
+    BatchProofError, BatchReport, CacheCapacity, CryptoHash, HostFunctions, MerklePath,
+    ProofBatchVerifier,
+};
+
+/// Identifier of a NEAR shard, to be used as the namespace of a [`MultiRootVerifier`].
+pub type ShardId = u64;
+
+/// Verifies proofs against several trusted roots, with a separate cache for each
+/// `(namespace, root)` pair.
+///
+/// Namespaces tell apart trees that may share a root, like the chunks of
+/// different shards. Use `()` when the root alone identifies the tree, or
+/// [`ShardId`] to keep the shards apart.
+#[derive(Debug)]
+pub struct MultiRootVerifier<HF: HostFunctions, N = ()> {
+    verifiers: HashMap<(N, CryptoHash), ProofBatchVerifier<HF>>,
+    /// capacity of the cache of every root
+    capacity: CacheCapacity,
+}
+
+impl<HF: HostFunctions, N: Eq + Hash> MultiRootVerifier<HF, N> {
+    pub fn new() -> Self {
+        Self::with_capacity(CacheCapacity::Unbounded)
+    }
+
+    /// Creates a verifier where the cache of each root never grows past `capacity`.
+    pub fn with_capacity(capacity: CacheCapacity) -> Self {
+        Self {
+            verifiers: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Returns the verifier pinned to `root` in `namespace`, creating it if needed.
+    pub fn verifier(&mut self, namespace: N, root: CryptoHash) -> &mut ProofBatchVerifier<HF> {
+        let capacity = self.capacity;
+        self.verifiers.entry((namespace, root)).or_insert_with(|| {
+            let mut verifier = ProofBatchVerifier::with_capacity(capacity);
+            verifier.trusted_root = Some(root);
+            verifier
+        })
+    }
+
+    /// Verifies that the given merkle proof and item hash yield `root`, see
+    /// [`ProofBatchVerifier::verify`].
+    pub fn verify(
+        &mut self,
+        namespace: N,
+        root: CryptoHash,
+        proof: &MerklePath,
+        item_hash: CryptoHash,
+    ) -> Result<(), BatchProofError> {
+        self.verifier(namespace, root)
+            .verify(root, proof, item_hash)
+    }
+
+    /// Verifies a batch of proofs of the same tree, stopping at the first one that
+    /// fails, see [`ProofBatchVerifier::verify_all`].
+    pub fn verify_all<'a>(
+        &mut self,
+        namespace: N,
+        root: CryptoHash,
+        proofs: impl IntoIterator<Item = (&'a MerklePath, CryptoHash)>,
+    ) -> Result<(), BatchProofError> {
+        self.verifier(namespace, root).verify_all(root, proofs)
+    }
+
+    /// Verifies every proof of a batch of the same tree, see
+    /// [`ProofBatchVerifier::verify_batch`].
+    pub fn verify_batch<'a>(
+        &mut self,
+        namespace: N,
+        root: CryptoHash,
+        proofs: impl IntoIterator<Item = (&'a MerklePath, CryptoHash)>,
+    ) -> Result<BatchReport, BatchProofError> {
+        self.verifier(namespace, root).verify_batch(root, proofs)
+    }
+
+    /// Drops the cache of `root` in `namespace`, returning its verifier if there was one.
+    pub fn remove(&mut self, namespace: N, root: CryptoHash) -> Option<ProofBatchVerifier<HF>> {
+        self.verifiers.remove(&(namespace, root))
+    }
+
+    /// Only keeps the caches for which `keep` returns true, e.g. to drop old blocks.
+    pub fn retain(&mut self, mut keep: impl FnMut(&N, &CryptoHash) -> bool) {
+        self.verifiers
+            .retain(|(namespace, root), _| keep(namespace, root));
+    }
+
+    /// Roots that currently have a cache, along with their namespace.
+    pub fn roots(&self) -> Vec<(&N, CryptoHash)> {
+        self.verifiers
+            .keys()
+            .map(|(namespace, root)| (namespace, *root))
+            .collect()
+    }
+
+    /// Number of nodes currently cached, over every root.
+    pub fn cache_len(&self) -> usize {
+        self.verifiers
+            .values()
+            .map(ProofBatchVerifier::cache_len)
+            .sum()
+    }
+}
+
+impl<HF: HostFunctions, N: Eq + Hash> Default for MultiRootVerifier<HF, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{item_hash, merklize, MockedHostFunctions};
+
+    #[test]
+    fn test_proofs_of_several_trees() {
+        let first = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let second = &[10, 20, 30, 40, 50, 60, 70, 80];
+        let (first_root, first_proofs) = merklize(first);
+        let (second_root, second_proofs) = merklize(second);
+
+        let mut verifier = MultiRootVerifier::<MockedHostFunctions, ShardId>::new();
+        for idx in 0..first.len() {
+            verifier
+                .verify(0, first_root, &first_proofs[idx], item_hash(&first[idx]))
+                .unwrap();
+            verifier
+                .verify(1, second_root, &second_proofs[idx], item_hash(&second[idx]))
+                .unwrap();
+        }
+        assert_eq!(verifier.roots().len(), 2);
+        assert_eq!(verifier.cache_len(), 14);
+
+        // a proof checked against the wrong tree still fails
+        assert!(matches!(
+            verifier.verify(0, first_root, &second_proofs[3], item_hash(&second[3])),
+            Err(BatchProofError::CacheMismatch { .. })
+        ));
+        // the same root in another namespace gets its own cache
+        verifier
+            .verify(1, first_root, &first_proofs[0], item_hash(&first[0]))
+            .unwrap();
+        assert_eq!(verifier.roots().len(), 3);
+
+        verifier.retain(|shard_id, _| *shard_id == 0);
+        assert_eq!(verifier.roots(), [(&0, first_root)]);
+        assert!(verifier.remove(0, first_root).is_some());
+        assert_eq!(verifier.cache_len(), 0);
+    }
+}
@@ -0,0 +1,394 @@
+//! Compact proofs for several leaves of the same tree.
+//!
+//! Independent [`MerklePath`]s for leaves of one tree repeat the siblings they share
+//! near the root, and carry siblings that can be derived from the other leaves. A
+//! [`MultiProof`] only keeps the hashes that cannot be derived from the leaves.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    vec::Vec,
+};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::{
+    node_coordinates, sibling_nodes, BatchProofError, CryptoHash, HostFunctions, Index, Level,
+    MerklePath, NodeCoordinates,
+};
+
+/// Position of a leaf in the tree.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, BorshSerialize, BorshDeserialize,
+)]
+pub struct LeafPosition {
+    pub level: u32,
+    pub index: u64,
+}
+
+impl LeafPosition {
+    fn coordinates(&self) -> (Level, Index) {
+        (self.level as Level, self.index as Index)
+    }
+
+    /// Whether the position exists in a tree whose root is at level 0, i.e. the
+    /// level holds more than `index` nodes.
+    fn is_valid(&self) -> bool {
+        self.index.checked_shr(self.level).unwrap_or(0) == 0 && Index::try_from(self.index).is_ok()
+    }
+}
+
+/// A proof that several leaves belong to the same tree.
+///
+/// `hashes` holds the siblings that cannot be derived from the leaves, ordered from
+/// the deepest level up to the root, and by index within a level.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct MultiProof {
+    leaf_positions: Vec<LeafPosition>,
+    hashes: Vec<CryptoHash>,
+}
+
+impl MultiProof {
+    /// Builds a multiproof out of the merkle paths of several leaves of the same tree.
+    /// Leaves keep the order of `paths`.
+    ///
+    /// An empty path is the path of the only leaf of a single-leaf tree, so it
+    /// cannot be combined with the paths of other leaves.
+    pub fn from_paths<'a>(
+        paths: impl IntoIterator<Item = &'a MerklePath>,
+    ) -> Result<Self, BatchProofError> {
+        let mut leaf_positions = Vec::new();
+        let mut given_nodes = HashMap::new();
+
+        for path in paths {
+            if path.is_empty() {
+                leaf_positions.push(LeafPosition { level: 0, index: 0 });
+                continue;
+            }
+            let (_, node_coordinates_to_calculate) = node_coordinates(path);
+            let (leaf_index, siblings) = sibling_nodes(path, &node_coordinates_to_calculate);
+            leaf_positions.push(LeafPosition {
+                level: path.len() as u32,
+                index: leaf_index as u64,
+            });
+
+            for NodeCoordinates { index, level, hash } in siblings {
+                let hash = hash.ok_or(BatchProofError::MalformedProof)?;
+                match given_nodes.insert((level, index), hash) {
+                    Some(previous) if previous != hash => {
+                        return Err(BatchProofError::ConflictingProofs { level, index })
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if !is_single_leaf_tree(&leaf_positions) {
+            return Err(BatchProofError::EmptyProof);
+        }
+
+        // every node on the way from a leaf to the root can be derived by the verifier
+        let mut derived_nodes = HashSet::new();
+        for position in leaf_positions.iter() {
+            let (mut level, mut index) = position.coordinates();
+            loop {
+                derived_nodes.insert((level, index));
+                if level == 0 {
+                    break;
+                }
+                level -= 1;
+                index /= 2;
+            }
+        }
+
+        let mut needed_nodes = given_nodes
+            .into_iter()
+            .filter(|(coordinates, _)| !derived_nodes.contains(coordinates))
+            .collect::<Vec<_>>();
+        needed_nodes.sort_by(|((level_a, index_a), _), ((level_b, index_b), _)| {
+            level_b.cmp(level_a).then(index_a.cmp(index_b))
+        });
+
+        Ok(Self {
+            leaf_positions,
+            hashes: needed_nodes.into_iter().map(|(_, hash)| hash).collect(),
+        })
+    }
+
+    /// Positions of the leaves covered by this proof.
+    pub fn leaf_positions(&self) -> &[LeafPosition] {
+        &self.leaf_positions
+    }
+
+    /// Sibling hashes carried by this proof.
+    pub fn hashes(&self) -> &[CryptoHash] {
+        &self.hashes
+    }
+
+    /// Computes the root of the tree from the hashes of the leaves, given in the same
+    /// order as [`Self::leaf_positions`]. Every leaf is processed in a single pass.
+    pub fn calculate_root_hash<HF: HostFunctions>(
+        &self,
+        leaf_hashes: &[CryptoHash],
+    ) -> Result<CryptoHash, BatchProofError> {
+        if leaf_hashes.len() != self.leaf_positions.len() {
+            return Err(BatchProofError::MalformedProof);
+        }
+        if leaf_hashes.is_empty() {
+            return Err(BatchProofError::EmptyProof);
+        }
+        // the root would otherwise pass as a leaf next to the leaves it is made of
+        if !is_single_leaf_tree(&self.leaf_positions) {
+            return Err(BatchProofError::MalformedProof);
+        }
+
+        // known nodes, grouped by level
+        let mut levels: BTreeMap<Level, BTreeMap<Index, CryptoHash>> = BTreeMap::new();
+        for (position, hash) in self.leaf_positions.iter().zip(leaf_hashes) {
+            // a leaf outside of the tree would be hashed into nodes nobody checks
+            if !position.is_valid() {
+                return Err(BatchProofError::MalformedProof);
+            }
+            let (level, index) = position.coordinates();
+            insert_node(&mut levels, level, index, *hash)?;
+        }
+
+        let mut hashes = self.hashes.iter();
+        while let Some((level, nodes)) = levels.pop_last() {
+            if level == 0 {
+                if hashes.next().is_some() || nodes.len() != 1 {
+                    return Err(BatchProofError::MalformedProof);
+                }
+                return nodes
+                    .get(&0)
+                    .copied()
+                    .ok_or(BatchProofError::MalformedProof);
+            }
+
+            let mut nodes = nodes.into_iter().peekable();
+            while let Some((index, hash)) = nodes.next() {
+                let parent = match index % 2 {
+                    0 => {
+                        let sibling = match nodes.peek() {
+                            Some((sibling_index, _)) if *sibling_index == index + 1 => {
+                                nodes.next().map(|(_, hash)| hash)
+                            }
+                            _ => hashes.next().copied(),
+                        }
+                        .ok_or(BatchProofError::MalformedProof)?;
+                        CryptoHash::hash_pair::<HF>(&hash, &sibling)
+                    }
+                    _ => {
+                        let sibling = hashes.next().ok_or(BatchProofError::MalformedProof)?;
+                        CryptoHash::hash_pair::<HF>(sibling, &hash)
+                    }
+                };
+                insert_node(&mut levels, level - 1, index / 2, parent)?;
+            }
+        }
+
+        Err(BatchProofError::MalformedProof)
+    }
+
+    /// Checks that every leaf belongs to the tree with the given root.
+    pub fn verify<HF: HostFunctions>(
+        &self,
+        expected_root: CryptoHash,
+        leaf_hashes: &[CryptoHash],
+    ) -> Result<(), BatchProofError> {
+        let root_hash = self.calculate_root_hash::<HF>(leaf_hashes)?;
+        if root_hash != expected_root {
+            return Err(BatchProofError::RootMismatch {
+                expected: expected_root,
+                computed: root_hash,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Whether a leaf at the root, i.e. the leaf of a single-leaf tree, only comes with
+/// leaves at the root too.
+fn is_single_leaf_tree(leaf_positions: &[LeafPosition]) -> bool {
+    !leaf_positions.iter().any(|position| position.level == 0)
+        || leaf_positions.iter().all(|position| position.level == 0)
+}
+
+fn insert_node(
+    levels: &mut BTreeMap<Level, BTreeMap<Index, CryptoHash>>,
+    level: Level,
+    index: Index,
+    hash: CryptoHash,
+) -> Result<(), BatchProofError> {
+    match levels.entry(level).or_default().insert(index, hash) {
+        Some(previous) if previous != hash => {
+            Err(BatchProofError::ConflictingProofs { level, index })
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{item_hash, merklize, MockedHostFunctions},
+        ProofBatchVerifier,
+    };
+
+    #[test]
+    fn test_multiproof_all_subsets() {
+        for size in 2..=9u32 {
+            let elements = (0..size).collect::<Vec<_>>();
+            let (root_hash, paths) = merklize(&elements);
+            let leaf_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+
+            for subset in 1..(1u32 << size) {
+                let leaves = (0..size as usize)
+                    .filter(|leaf| subset & (1 << leaf) != 0)
+                    .collect::<Vec<_>>();
+                let multiproof =
+                    MultiProof::from_paths(leaves.iter().map(|leaf| &paths[*leaf])).unwrap();
+                let hashes = leaves
+                    .iter()
+                    .map(|leaf| leaf_hashes[*leaf])
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    multiproof.verify::<MockedHostFunctions>(root_hash, &hashes),
+                    Ok(()),
+                    "size {} leaves {:?}",
+                    size,
+                    leaves
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_multiproof_is_smaller_than_paths() {
+        let elements = (0..16).collect::<Vec<_>>();
+        let (_, paths) = merklize(&elements);
+
+        // all leaves can be derived from each other
+        let multiproof = MultiProof::from_paths(paths.iter()).unwrap();
+        assert!(multiproof.hashes().is_empty());
+
+        // two neighbours only need the siblings of their parent's path
+        let multiproof = MultiProof::from_paths([&paths[4], &paths[5]]).unwrap();
+        assert_eq!(multiproof.hashes().len(), 3);
+        assert_eq!(
+            multiproof.leaf_positions(),
+            &[
+                LeafPosition { level: 4, index: 4 },
+                LeafPosition { level: 4, index: 5 }
+            ]
+        );
+
+        let serialized = multiproof.try_to_vec().unwrap();
+        assert_eq!(MultiProof::try_from_slice(&serialized).unwrap(), multiproof);
+    }
+
+    #[test]
+    fn test_multiproof_wrong_leaf() {
+        let elements = (0..8).collect::<Vec<_>>();
+        let (root_hash, paths) = merklize(&elements);
+        let leaf_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+
+        let multiproof = MultiProof::from_paths([&paths[1], &paths[6]]).unwrap();
+        assert!(matches!(
+            multiproof.verify::<MockedHostFunctions>(root_hash, &[leaf_hashes[1], leaf_hashes[5]]),
+            Err(BatchProofError::RootMismatch { .. })
+        ));
+        assert_eq!(
+            multiproof.verify::<MockedHostFunctions>(root_hash, &[leaf_hashes[1]]),
+            Err(BatchProofError::MalformedProof)
+        );
+    }
+
+    #[test]
+    fn test_multiproof_rejects_leaves_outside_of_the_tree() {
+        let elements = (0..8).collect::<Vec<_>>();
+        let (root_hash, paths) = merklize(&elements);
+        let leaf_hashes = [item_hash(&0), item_hash(&42)];
+
+        // a leaf that cannot exist on its level, hashed into a stray node at level 0
+        let mut forged = MultiProof::from_paths([&paths[0]]).unwrap();
+        forged
+            .leaf_positions
+            .push(LeafPosition { level: 1, index: 5 });
+        forged.hashes.push(CryptoHash::default());
+        assert_eq!(
+            forged.verify::<MockedHostFunctions>(root_hash, &leaf_hashes),
+            Err(BatchProofError::MalformedProof)
+        );
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        assert_eq!(
+            verifier.verify_multiproof(root_hash, &forged, &leaf_hashes),
+            Err(BatchProofError::MalformedProof)
+        );
+
+        let mut forged = MultiProof::from_paths([&paths[0]]).unwrap();
+        forged.leaf_positions.push(LeafPosition {
+            level: 3,
+            index: u64::MAX,
+        });
+        assert_eq!(
+            forged.verify::<MockedHostFunctions>(root_hash, &leaf_hashes),
+            Err(BatchProofError::MalformedProof)
+        );
+    }
+
+    #[test]
+    fn test_multiproof_conflicting_paths() {
+        let elements = (0..8).collect::<Vec<_>>();
+        let (_, paths) = merklize(&elements);
+
+        let mut forged_path = paths[2].clone();
+        forged_path[2].hash = CryptoHash::default();
+        assert_eq!(
+            MultiProof::from_paths([&paths[1], &forged_path]),
+            Err(BatchProofError::ConflictingProofs { level: 1, index: 1 })
+        );
+    }
+
+    #[test]
+    fn test_multiproof_single_leaf_tree() {
+        let (root_hash, paths) = merklize(&[7]);
+        assert!(paths[0].is_empty());
+        assert_eq!(root_hash, item_hash(&7));
+
+        let multiproof = MultiProof::from_paths([&paths[0]]).unwrap();
+        assert_eq!(
+            multiproof.leaf_positions(),
+            [LeafPosition { level: 0, index: 0 }]
+        );
+        assert!(multiproof.hashes().is_empty());
+        assert_eq!(
+            multiproof.verify::<MockedHostFunctions>(root_hash, &[item_hash(&7)]),
+            Ok(())
+        );
+        assert_eq!(
+            multiproof.verify::<MockedHostFunctions>(root_hash, &[item_hash(&8)]),
+            Err(BatchProofError::RootMismatch {
+                expected: root_hash,
+                computed: item_hash(&8)
+            })
+        );
+
+        // the root of a larger tree is not one of its leaves
+        let elements = (0..8).collect::<Vec<_>>();
+        let (root_hash, paths) = merklize(&elements);
+        let empty_path = Vec::new();
+        assert_eq!(
+            MultiProof::from_paths([&paths[0], &empty_path]),
+            Err(BatchProofError::EmptyProof)
+        );
+        let mut forged = MultiProof::from_paths([&paths[0]]).unwrap();
+        forged
+            .leaf_positions
+            .push(LeafPosition { level: 0, index: 0 });
+        assert_eq!(
+            forged.verify::<MockedHostFunctions>(root_hash, &[item_hash(&0), root_hash]),
+            Err(BatchProofError::MalformedProof)
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! Conversions between this crate's primitives and the ones in `near-primitives`.
+
+use std::vec::Vec;
+
+use near_crypto::{PublicKey as NearPublicKey, Signature as NearSignature};
+use near_primitives::{
+    hash::CryptoHash as NearCryptoHash,
+    merkle as near_merkle,
+    transaction::ExecutionOutcomeWithIdAndProof,
+    types::validator_stake::ValidatorStake as NearValidatorStake,
+    views::{
+        validator_stake_view::ValidatorStakeView, BlockHeaderInnerLiteView,
+        LightClientBlockLiteView, LightClientBlockView,
+    },
+};
+
+use crate::{
+    light_client::{BlockHeaderInnerLite, LightClientBlockLite, LightClientExecutionProof},
+    light_client_header::{LightClientBlock, PublicKey, Signature, ValidatorStake},
+    primitives::{CryptoHash, Direction, MerklePath, MerklePathItem},
+};
+
+impl From<NearCryptoHash> for CryptoHash {
+    fn from(hash: NearCryptoHash) -> Self {
+        CryptoHash(hash.0)
+    }
+}
+
+impl From<CryptoHash> for NearCryptoHash {
+    fn from(hash: CryptoHash) -> Self {
+        NearCryptoHash(hash.0)
+    }
+}
+
+impl From<near_merkle::Direction> for Direction {
+    fn from(direction: near_merkle::Direction) -> Self {
+        match direction {
+            near_merkle::Direction::Left => Direction::Left,
+            near_merkle::Direction::Right => Direction::Right,
+        }
+    }
+}
+
+impl From<Direction> for near_merkle::Direction {
+    fn from(direction: Direction) -> Self {
+        match direction {
+            Direction::Left => near_merkle::Direction::Left,
+            Direction::Right => near_merkle::Direction::Right,
+        }
+    }
+}
+
+impl From<near_merkle::MerklePathItem> for MerklePathItem {
+    fn from(item: near_merkle::MerklePathItem) -> Self {
+        MerklePathItem {
+            hash: item.hash.into(),
+            direction: item.direction.into(),
+        }
+    }
+}
+
+impl From<MerklePathItem> for near_merkle::MerklePathItem {
+    fn from(item: MerklePathItem) -> Self {
+        near_merkle::MerklePathItem {
+            hash: item.hash.into(),
+            direction: item.direction.into(),
+        }
+    }
+}
+
+/// Converts a `near_primitives` merkle path into this crate's representation.
+pub fn from_near_path(path: &near_merkle::MerklePath) -> MerklePath {
+    path.iter().cloned().map(Into::into).collect()
+}
+
+/// Converts a merkle path into its `near_primitives` representation.
+pub fn into_near_path(path: &MerklePath) -> near_merkle::MerklePath {
+    path.iter().cloned().map(Into::into).collect::<Vec<_>>()
+}
+
+impl From<BlockHeaderInnerLiteView> for BlockHeaderInnerLite {
+    fn from(view: BlockHeaderInnerLiteView) -> Self {
+        BlockHeaderInnerLite {
+            height: view.height,
+            epoch_id: view.epoch_id.into(),
+            next_epoch_id: view.next_epoch_id.into(),
+            prev_state_root: view.prev_state_root.into(),
+            outcome_root: view.outcome_root.into(),
+            timestamp: view.timestamp_nanosec,
+            next_bp_hash: view.next_bp_hash.into(),
+            block_merkle_root: view.block_merkle_root.into(),
+        }
+    }
+}
+
+impl From<LightClientBlockLiteView> for LightClientBlockLite {
+    fn from(view: LightClientBlockLiteView) -> Self {
+        LightClientBlockLite {
+            prev_block_hash: view.prev_block_hash.into(),
+            inner_rest_hash: view.inner_rest_hash.into(),
+            inner_lite: view.inner_lite.into(),
+        }
+    }
+}
+
+/// Builds a light client execution proof out of the parts of an
+/// `EXPERIMENTAL_light_client_proof` response.
+pub fn execution_proof_from_near(
+    outcome_proof: &ExecutionOutcomeWithIdAndProof,
+    outcome_root_proof: &near_merkle::MerklePath,
+    block_header_lite: LightClientBlockLiteView,
+    block_proof: &near_merkle::MerklePath,
+) -> LightClientExecutionProof {
+    LightClientExecutionProof {
+        outcome_hashes: outcome_proof
+            .outcome_with_id
+            .to_hashes()
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+        outcome_proof: from_near_path(&outcome_proof.proof),
+        outcome_root_proof: from_near_path(outcome_root_proof),
+        block_header_lite: block_header_lite.into(),
+        block_proof: from_near_path(block_proof),
+    }
+}
+
+impl From<NearPublicKey> for PublicKey {
+    fn from(public_key: NearPublicKey) -> Self {
+        match public_key {
+            NearPublicKey::ED25519(public_key) => PublicKey::Ed25519(public_key.0),
+            NearPublicKey::SECP256K1(public_key) => PublicKey::Secp256k1(
+                public_key
+                    .as_ref()
+                    .try_into()
+                    .expect("secp256k1 public keys are 64 bytes long"),
+            ),
+        }
+    }
+}
+
+impl From<NearSignature> for Signature {
+    fn from(signature: NearSignature) -> Self {
+        match signature {
+            NearSignature::ED25519(signature) => Signature::Ed25519(signature.to_bytes()),
+            NearSignature::SECP256K1(signature) => Signature::Secp256k1(signature.into()),
+        }
+    }
+}
+
+impl From<ValidatorStakeView> for ValidatorStake {
+    fn from(view: ValidatorStakeView) -> Self {
+        let (account_id, public_key, stake) = NearValidatorStake::from(view).destructure();
+        ValidatorStake {
+            account_id: account_id.into(),
+            public_key: public_key.into(),
+            stake,
+        }
+    }
+}
+
+impl From<LightClientBlockView> for LightClientBlock {
+    fn from(view: LightClientBlockView) -> Self {
+        LightClientBlock {
+            prev_block_hash: view.prev_block_hash.into(),
+            next_block_inner_hash: view.next_block_inner_hash.into(),
+            inner_lite: view.inner_lite.into(),
+            inner_rest_hash: view.inner_rest_hash.into(),
+            next_bps: view
+                .next_bps
+                .map(|next_bps| next_bps.into_iter().map(Into::into).collect()),
+            approvals_after_next: view
+                .approvals_after_next
+                .into_iter()
+                .map(|approval| approval.map(Into::into))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Parallel verification of a batch of proofs, behind the `rayon` feature.
+//!
+//! Hashing is the expensive part of verifying a proof, so it is done in two passes.
+//! First, the paths of all the proofs are hashed in parallel into a memo of
+//! `hash_pair` results, keyed by the 64 bytes each one was computed from. A path
+//! stops at the first node already in the cache, and pairs shared by several paths
+//! are only hashed once.
+//!
+//! The proofs are then checked and committed to the cache one after the other, in
+//! their original order, as [`ProofBatchVerifier::verify_batch`] does but taking
+//! the hashes from the memo. The cache is never shared between threads, so the
+//! outcome is exactly the one of the sequential verification.
+
+use core::cell::Cell;
+use std::{collections::HashMap, sync::Mutex, vec::Vec};
+
+use rayon::prelude::*;
+
+use crate::{
+    cache::CachedNodes, node_coordinates, BatchProofError, BatchReport, CryptoHash, Direction,
+    HashCounts, HostFunctions, MerklePath, NodeCoordinates, ProofBatchVerifier, ProofReport,
+};
+
+/// Number of independently locked shards of [`SharedHashes`].
+const SHARDS: usize = 64;
+
+/// Hashes of node pairs, keyed by the 64 bytes they were computed from.
+struct SharedHashes {
+    shards: Vec<Mutex<HashMap<[u8; 64], CryptoHash>>>,
+}
+
+impl SharedHashes {
+    fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn input(left: &CryptoHash, right: &CryptoHash) -> [u8; 64] {
+        let mut input = [0u8; 64];
+        input[..32].copy_from_slice(&left.0);
+        input[32..].copy_from_slice(&right.0);
+        input
+    }
+
+    fn shard(&self, input: &[u8; 64]) -> &Mutex<HashMap<[u8; 64], CryptoHash>> {
+        // inputs are made of hashes, so any of their bytes spreads them evenly
+        &self.shards[input[0] as usize % SHARDS]
+    }
+
+    fn get(&self, input: &[u8; 64]) -> Option<CryptoHash> {
+        self.shard(input)
+            .lock()
+            .expect("no worker panics while holding the lock")
+            .get(input)
+            .copied()
+    }
+
+    /// Hashes the pair, unless a worker already did.
+    fn hash_pair<HF: HostFunctions>(&self, left: &CryptoHash, right: &CryptoHash) -> CryptoHash {
+        let input = Self::input(left, right);
+        if let Some(hash) = self.get(&input) {
+            return hash;
+        }
+        let hash = CryptoHash::hash_pair::<HF>(left, right);
+        self.shard(&input)
+            .lock()
+            .expect("no worker panics while holding the lock")
+            .insert(input, hash);
+        hash
+    }
+
+    /// Hashes the nodes on the path from the item up to the root, up to the first
+    /// one found in `cached_nodes`.
+    fn hash_path<HF: HostFunctions>(
+        &self,
+        cached_nodes: &CachedNodes,
+        proof: &MerklePath,
+        item_hash: CryptoHash,
+    ) {
+        let (_, coordinates) = node_coordinates(proof);
+        let mut hash = item_hash;
+        for (item, NodeCoordinates { level, index, .. }) in
+            proof.iter().zip(coordinates.iter().rev())
+        {
+            hash = match item.direction {
+                Direction::Left => self.hash_pair::<HF>(&item.hash, &hash),
+                Direction::Right => self.hash_pair::<HF>(&hash, &item.hash),
+            };
+            if cached_nodes.inner.contains_key(&(*level, *index)) {
+                break;
+            }
+        }
+    }
+}
+
+impl<HF: HostFunctions> ProofBatchVerifier<HF> {
+    /// Same as [`Self::verify_batch`], spreading the hashing over the threads of
+    /// the rayon pool. The report and the cache end up exactly as with
+    /// [`Self::verify_batch`].
+    pub fn verify_batch_parallel<'a>(
+        &mut self,
+        expected_root: CryptoHash,
+        proofs: impl IntoIterator<Item = (&'a MerklePath, CryptoHash)>,
+    ) -> Result<BatchReport, BatchProofError> {
+        self.verify_batch_memoized(expected_root, proofs)
+            .map(|(report, _)| report)
+    }
+
+    /// Same as [`Self::verify_batch_parallel`], also returning the number of pairs
+    /// missing from the memo, which had to be hashed while committing.
+    fn verify_batch_memoized<'a>(
+        &mut self,
+        expected_root: CryptoHash,
+        proofs: impl IntoIterator<Item = (&'a MerklePath, CryptoHash)>,
+    ) -> Result<(BatchReport, usize), BatchProofError> {
+        self.pin_root(expected_root)?;
+        let proofs = proofs.into_iter().collect::<Vec<_>>();
+
+        let hashes = SharedHashes::new();
+        let cached_nodes = &self.cached_nodes;
+        proofs
+            .par_iter()
+            .for_each(|(proof, item_hash)| hashes.hash_path::<HF>(cached_nodes, proof, *item_hash));
+
+        // the memo lacks the nodes above the ones that were cached, which are only
+        // needed if earlier proofs of the batch evicted them since
+        let misses = Cell::new(0);
+        let hash_pair = |left: &CryptoHash, right: &CryptoHash| {
+            hashes
+                .get(&SharedHashes::input(left, right))
+                .unwrap_or_else(|| {
+                    misses.set(misses.get() + 1);
+                    CryptoHash::hash_pair::<HF>(left, right)
+                })
+        };
+        let proofs = proofs
+            .into_iter()
+            .map(|(proof, item_hash)| {
+                let mut counts = HashCounts::default();
+                let result = self.commit_root_hash_with(proof, item_hash, &mut counts, hash_pair);
+                ProofReport::new(result, counts.computed, counts.cached)
+            })
+            .collect();
+        Ok((BatchReport { proofs }, misses.get()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{
+            count_sha256_calls, item_hash, merklize, sha256_calls, CountingHostFunctions,
+            MockedHostFunctions,
+        },
+        CacheCapacity,
+    };
+
+    #[test]
+    fn test_verify_batch_parallel_matches_sequential() {
+        let elements = (0..100u32).collect::<Vec<_>>();
+        let (root_hash, merkle_proofs) = merklize(&elements);
+        let mut item_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+        item_hashes[17] = item_hash(&1000);
+        let mut forged_proof = merkle_proofs[42].clone();
+        forged_proof[3].hash = CryptoHash::default();
+
+        let batches = [
+            merkle_proofs[..50]
+                .iter()
+                .zip(item_hashes[..50].iter().copied())
+                .collect::<Vec<_>>(),
+            merkle_proofs
+                .iter()
+                .chain([&forged_proof])
+                .zip(item_hashes.iter().chain([&item_hashes[42]]).copied())
+                .collect::<Vec<_>>(),
+        ];
+
+        let mut sequential = ProofBatchVerifier::<MockedHostFunctions>::new();
+        let mut parallel = ProofBatchVerifier::<MockedHostFunctions>::new();
+        for batch in batches {
+            let expected = sequential
+                .verify_batch(root_hash, batch.iter().copied())
+                .unwrap();
+            let (report, misses) = parallel
+                .verify_batch_memoized(root_hash, batch.iter().copied())
+                .unwrap();
+            assert_eq!(report, expected);
+            assert_eq!(misses, 0);
+            assert_eq!(parallel.cached_nodes, sequential.cached_nodes);
+        }
+    }
+
+    #[test]
+    fn test_verify_batch_parallel_stops_at_cached_nodes() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+
+        let mut verifier = ProofBatchVerifier::<CountingHostFunctions>::new();
+        let _counting = count_sha256_calls();
+        verifier
+            .verify(root_hash, &merkle_proofs[0], item_hash(&1))
+            .unwrap();
+        // the leaf level parent is shared with leaf 0, so nothing above it is hashed
+        let calls = sha256_calls();
+        let (report, misses) = verifier
+            .verify_batch_memoized(root_hash, [(&merkle_proofs[1], item_hash(&2))])
+            .unwrap();
+        assert!(report.is_valid());
+        // the one pair hashed was hashed by the parallel pass
+        assert_eq!(sha256_calls() - calls, 1);
+        assert_eq!(misses, 0);
+    }
+
+    #[test]
+    fn test_verify_batch_parallel_evicted_nodes() {
+        let elements = (0..16u32).collect::<Vec<_>>();
+        let (root_hash, merkle_proofs) = merklize(&elements);
+        let item_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+        let batch = [8, 1].map(|leaf| (&merkle_proofs[leaf], item_hashes[leaf]));
+
+        let capacity = CacheCapacity::Entries(4);
+        let mut sequential = ProofBatchVerifier::<MockedHostFunctions>::with_capacity(capacity);
+        let mut parallel = ProofBatchVerifier::<MockedHostFunctions>::with_capacity(capacity);
+        for verifier in [&mut sequential, &mut parallel] {
+            verifier
+                .verify(root_hash, &merkle_proofs[0], item_hashes[0])
+                .unwrap();
+        }
+
+        // the path of leaf 1 stops at the parent of leaves 0 and 1, but leaf 8 evicts
+        // it along with the two nodes above it, so these are hashed while committing
+        let expected = sequential.verify_batch(root_hash, batch).unwrap();
+        let (report, misses) = parallel.verify_batch_memoized(root_hash, batch).unwrap();
+        assert_eq!(report, expected);
+        assert_eq!(parallel.cached_nodes, sequential.cached_nodes);
+        assert_eq!(misses, 2);
+    }
+}
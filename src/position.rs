@@ -0,0 +1,244 @@
+//! Exact node positions in NEAR's layout, for a tree of known size.
+//!
+//! [`crate::ProofBatchVerifier::get_node_coordinates`] only looks at the directions
+//! of a path and assumes a perfect tree whose depth is the length of the path. In
+//! NEAR's `merklize`, a node left without a sibling is carried up unchanged, so
+//! paths of the same tree can have different lengths. Knowing the size of the tree
+//! and the index of the leaf, [`PathPositions`] places every node exactly.
+
+use std::vec::Vec;
+
+use crate::{BatchProofError, Direction, Index, LeafIndex, Level, MerklePath};
+
+/// Positions of the nodes on the path of a leaf, in a tree of known size.
+///
+/// Leaves sit at level `height` and the root at level 0; a node at `(level, index)`
+/// has its children at `(level + 1, 2 * index)` and `(level + 1, 2 * index + 1)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPositions {
+    height: Level,
+    /// position of every item of the merkle path, in the same order
+    siblings: Vec<(Level, Index)>,
+    /// position of every node from the leaf up to the root, carried nodes included
+    path: Vec<(Level, Index)>,
+}
+
+impl PathPositions {
+    /// Positions of the path of `leaf_index` in a tree of `tree_size` leaves.
+    pub fn new(leaf_index: LeafIndex, tree_size: usize) -> Result<Self, BatchProofError> {
+        if leaf_index >= tree_size {
+            return Err(BatchProofError::LeafIndexOutOfBounds {
+                leaf_index,
+                tree_size,
+            });
+        }
+
+        let height = tree_height(tree_size)?;
+        let mut siblings = Vec::new();
+        let mut path = Vec::with_capacity(height + 1);
+        path.push((height, leaf_index));
+
+        let (mut index, mut level_size) = (leaf_index, tree_size);
+        for level in (1..=height).rev() {
+            // the last node of a level with an odd size has no sibling
+            if index ^ 1 < level_size {
+                siblings.push((level, index ^ 1));
+            }
+            index /= 2;
+            level_size = level_size.div_ceil(2);
+            path.push((level - 1, index));
+        }
+
+        Ok(Self {
+            height,
+            siblings,
+            path,
+        })
+    }
+
+    /// Positions of the nodes of `proof`, checking that it is the path of
+    /// `leaf_index` in a tree of `tree_size` leaves.
+    ///
+    /// Fails if the path does not have the expected length, or if its directions
+    /// point to another leaf.
+    pub fn from_path(
+        proof: &MerklePath,
+        leaf_index: LeafIndex,
+        tree_size: usize,
+    ) -> Result<Self, BatchProofError> {
+        let positions = Self::new(leaf_index, tree_size)?;
+        let fits = proof.len() == positions.siblings.len()
+            && proof
+                .iter()
+                .zip(positions.siblings.iter())
+                .all(|(item, (_, index))| item.direction == Self::direction_of(*index));
+        if !fits {
+            return Err(BatchProofError::InvalidPathShape {
+                leaf_index,
+                tree_size,
+            });
+        }
+        Ok(positions)
+    }
+
+    /// Side on which a sibling at `index` sits, relative to the node on the path.
+    fn direction_of(index: Index) -> Direction {
+        match index % 2 {
+            0 => Direction::Left,
+            _ => Direction::Right,
+        }
+    }
+
+    /// Level of the leaves.
+    pub fn height(&self) -> Level {
+        self.height
+    }
+
+    /// Position of the leaf.
+    pub fn leaf(&self) -> (Level, Index) {
+        self.path[0]
+    }
+
+    /// Position of every item of the merkle path, in the same order.
+    pub fn siblings(&self) -> &[(Level, Index)] {
+        &self.siblings
+    }
+
+    /// Position of every node from the leaf up to the root. A node without a sibling
+    /// has the same hash as its parent.
+    pub fn path(&self) -> &[(Level, Index)] {
+        &self.path
+    }
+}
+
+/// Level of the leaves of a tree of `tree_size` leaves, the root being at level 0.
+///
+/// Fails if the tree is more than half as large as `usize` allows, as its height
+/// cannot be computed.
+pub(crate) fn tree_height(tree_size: usize) -> Result<Level, BatchProofError> {
+    tree_size
+        .checked_next_power_of_two()
+        .map(|size| size.trailing_zeros() as Level)
+        .ok_or(BatchProofError::TreeTooLarge { tree_size })
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{item_hash, merklize, MockedHostFunctions},
+        CryptoHash,
+    };
+
+    #[test]
+    fn test_positions_match_near_layout() {
+        for size in 1..=1024u32 {
+            let elements = (0..size).collect::<Vec<_>>();
+            let (root_hash, paths) = merklize(&elements);
+
+            // every position holds a single hash over all the paths of the tree
+            let height = tree_height(size as usize).unwrap();
+            let mut nodes = (0..=height)
+                .map(|level| vec![None; 1 << level])
+                .collect::<Vec<_>>();
+            let mut insert = |(level, index): (Level, Index), hash: CryptoHash| {
+                assert_eq!(
+                    *nodes[level][index].get_or_insert(hash),
+                    hash,
+                    "size {} position {:?}",
+                    size,
+                    (level, index)
+                );
+            };
+            let leaf_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+            for (leaf_index, path) in paths.iter().enumerate() {
+                let positions = PathPositions::from_path(path, leaf_index, size as usize)
+                    .unwrap_or_else(|e| panic!("size {} leaf {}: {}", size, leaf_index, e));
+                insert(positions.leaf(), leaf_hashes[leaf_index]);
+                path.iter()
+                    .zip(positions.siblings())
+                    .for_each(|(item, position)| insert(*position, item.hash));
+            }
+
+            // rebuilding the tree level by level, a node without a sibling being
+            // carried up, puts every proven sibling at its position
+            let mut level = height;
+            let mut level_nodes = leaf_hashes;
+            while level > 0 {
+                level_nodes = level_nodes
+                    .chunks(2)
+                    .enumerate()
+                    .map(|(index, pair)| {
+                        pair.iter()
+                            .enumerate()
+                            .for_each(|(offset, hash)| insert((level, 2 * index + offset), *hash));
+                        match pair {
+                            [left, right] => {
+                                CryptoHash::hash_pair::<MockedHostFunctions>(left, right)
+                            }
+                            _ => pair[0],
+                        }
+                    })
+                    .collect();
+                level -= 1;
+            }
+            assert_eq!(level_nodes, [root_hash], "size {}", size);
+        }
+    }
+
+    #[test]
+    fn test_impossible_shapes() {
+        let elements = (0..5u32).collect::<Vec<_>>();
+        let (_, paths) = merklize(&elements);
+        let invalid = |leaf_index, tree_size| {
+            Err(BatchProofError::InvalidPathShape {
+                leaf_index,
+                tree_size,
+            })
+        };
+
+        // the last leaf of 5 is carried up twice
+        assert_eq!(paths[4].len(), 1);
+        let positions = PathPositions::from_path(&paths[4], 4, 5).unwrap();
+        assert_eq!(positions.siblings(), &[(1, 0)]);
+        assert_eq!(positions.path(), &[(3, 4), (2, 2), (1, 1), (0, 0)]);
+
+        // right shape, wrong leaf
+        assert_eq!(PathPositions::from_path(&paths[0], 1, 5), invalid(1, 5));
+        // wrong size
+        assert_eq!(PathPositions::from_path(&paths[4], 4, 6), invalid(4, 6));
+        assert_eq!(PathPositions::from_path(&paths[0], 0, 4), invalid(0, 4));
+        // truncated and extended paths
+        assert_eq!(
+            PathPositions::from_path(&paths[0][..2].to_vec(), 0, 5),
+            invalid(0, 5)
+        );
+        let mut extended = paths[4].clone();
+        extended.push(paths[4][0].clone());
+        assert_eq!(PathPositions::from_path(&extended, 4, 5), invalid(4, 5));
+
+        assert_eq!(
+            PathPositions::from_path(&paths[0], 5, 5),
+            Err(BatchProofError::LeafIndexOutOfBounds {
+                leaf_index: 5,
+                tree_size: 5
+            })
+        );
+        assert!(PathPositions::new(0, 0).is_err());
+        assert_eq!(PathPositions::new(0, 1).unwrap().path(), &[(0, 0)]);
+
+        // the height of trees over half of `usize::MAX` leaves overflows
+        for tree_size in [(usize::MAX >> 1) + 2, usize::MAX] {
+            assert_eq!(
+                PathPositions::from_path(&paths[0], 0, tree_size),
+                Err(BatchProofError::TreeTooLarge { tree_size })
+            );
+        }
+        assert_eq!(
+            PathPositions::new(0, (usize::MAX >> 1) + 1)
+                .unwrap()
+                .height(),
+            usize::BITS as Level - 1
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Cache of the nodes of a tree of known size, at their exact positions.
+//!
+//! Shared by the verifiers that know the index of every leaf and the size of the
+//! tree, like the block merkle tree or the encoded parts of a chunk. Positions come
+//! from [`PathPositions`], so unlike the coordinates of a
+//! [`crate::ProofBatchVerifier`], siblings are exactly placed too and get cached
+//! along with the path they prove.
+
+use core::marker::PhantomData;
+
+use crate::{
+    cache::{CachedNodes, PathWalk},
+    position::tree_height,
+    BatchProofError, CacheCapacity, ComputedNodes, CryptoHash, Direction, HashCounts,
+    HostFunctions, LeafIndex, MerklePath, PathPositions,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct PositionedTree<HF: HostFunctions> {
+    pub(crate) root: CryptoHash,
+    pub(crate) tree_size: usize,
+    pub(crate) cached_nodes: CachedNodes,
+    _hf: PhantomData<HF>,
+}
+
+impl<HF: HostFunctions> PositionedTree<HF> {
+    pub(crate) fn with_capacity(
+        root: CryptoHash,
+        tree_size: usize,
+        capacity: CacheCapacity,
+    ) -> Self {
+        Self {
+            root,
+            tree_size,
+            cached_nodes: CachedNodes::with_capacity(capacity),
+            _hf: PhantomData,
+        }
+    }
+
+    /// Recomputes the path of the leaf and commits it to the cache if it lands on
+    /// the root, keeping track of the hashes that were needed.
+    pub(crate) fn commit_leaf(
+        &mut self,
+        leaf_index: LeafIndex,
+        leaf_hash: CryptoHash,
+        proof: &MerklePath,
+        counts: &mut HashCounts,
+    ) -> Result<CryptoHash, BatchProofError> {
+        let positions = PathPositions::from_path(proof, leaf_index, self.tree_size)?;
+        let mut walk = PathWalk::default();
+        self.cached_nodes
+            .record(&mut walk, positions.leaf(), leaf_hash)?;
+
+        let mut hash = leaf_hash;
+        let mut siblings = proof.iter().zip(positions.siblings()).peekable();
+        for &(level, index) in positions.path().iter().skip(1) {
+            let sibling = siblings.next_if(|(_, (sibling_level, sibling_index))| {
+                *sibling_level == level + 1 && sibling_index / 2 == index
+            });
+            // without a sibling, the node is carried up as is
+            let (node_hash, cached) =
+                self.cached_nodes
+                    .step(&mut walk, (level, index), || match sibling {
+                        Some((item, _)) => {
+                            counts.computed += 1;
+                            match item.direction {
+                                Direction::Left => CryptoHash::hash_pair::<HF>(&item.hash, &hash),
+                                Direction::Right => CryptoHash::hash_pair::<HF>(&hash, &item.hash),
+                            }
+                        }
+                        None => hash,
+                    })?;
+            hash = node_hash;
+            match sibling {
+                Some(_) if cached => counts.cached += 1,
+                // the sibling is proven along with the path
+                Some((item, sibling_position)) => {
+                    walk.computed_nodes.push((*sibling_position, item.hash))
+                }
+                None => {}
+            }
+        }
+
+        if hash != self.root {
+            return Err(BatchProofError::RootMismatch {
+                expected: self.root,
+                computed: hash,
+            });
+        }
+        self.cached_nodes
+            .commit(walk.computed_nodes, &walk.used_nodes, positions.leaf());
+        Ok(hash)
+    }
+
+    /// Removes the cached nodes that only the leaf at `leaf_index` contributed.
+    pub(crate) fn forget_leaf(&mut self, leaf_index: LeafIndex) {
+        // nothing can be cached for a tree too large to place its leaves
+        if let Ok(height) = tree_height(self.tree_size) {
+            self.cached_nodes.forget_leaf((height, leaf_index));
+        }
+    }
+
+    /// Moves to the root of the same append-only tree with `tree_size` leaves,
+    /// keeping the cached nodes that cover a full power of two of leaves of both
+    /// trees, as those leaves did not change.
+    pub(crate) fn resize(&mut self, root: CryptoHash, tree_size: usize) {
+        let complete_leaves = self.tree_size.min(tree_size);
+        let mut cached_nodes = CachedNodes::with_capacity(self.cached_nodes.capacity());
+        // no node of a tree too large to place its leaves can be kept
+        if let (Ok(old_height), Ok(new_height)) =
+            (tree_height(self.tree_size), tree_height(tree_size))
+        {
+            for (&(_, leaf_index), nodes) in self.cached_nodes.path_item_cache_mapping.iter() {
+                let kept = nodes
+                    .iter()
+                    .filter_map(|&(level, index)| {
+                        let hash = self.cached_nodes.inner.get(&(level, index))?;
+                        // number of levels between the node and the leaves
+                        let above_leaves = old_height - level;
+                        if (index + 1) << above_leaves > complete_leaves {
+                            return None;
+                        }
+                        Some(((new_height - above_leaves, index), *hash))
+                    })
+                    .collect::<ComputedNodes>();
+                cached_nodes.commit(kept, &[], (new_height, leaf_index));
+            }
+        }
+
+        self.root = root;
+        self.tree_size = tree_size;
+        self.cached_nodes = cached_nodes;
+    }
+}
@@ -0,0 +1,133 @@
+//! Minimal merkle primitives, borsh-compatible with the ones in `near-primitives`.
+//!
+//! They are defined in this crate so that it can be built without `std`. With the
+//! `near` feature enabled, they can be converted from and into NEAR's types.
+
+use core::fmt;
+use std::vec::Vec;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::host_functions::HostFunctions;
+
+#[derive(
+    Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, BorshSerialize, BorshDeserialize,
+)]
+pub struct CryptoHash(pub [u8; 32]);
+
+impl CryptoHash {
+    /// Hashes the given bytes with the host's sha256.
+    pub fn hash_bytes<HF: HostFunctions>(bytes: &[u8]) -> CryptoHash {
+        CryptoHash(HF::sha256(bytes))
+    }
+
+    /// Hashes a pair of child nodes into their parent, see [`HostFunctions::sha256_pair`].
+    ///
+    /// Same as hashing the borsh serialization of `(left, right)`, without allocating.
+    pub fn hash_pair<HF: HostFunctions>(left: &CryptoHash, right: &CryptoHash) -> CryptoHash {
+        CryptoHash(HF::sha256_pair(&left.0, &right.0))
+    }
+
+    /// Hashes the borsh serialization of `value` with the host's sha256.
+    pub fn hash_borsh<HF: HostFunctions, T: BorshSerialize>(value: &T) -> CryptoHash {
+        let serialized = value.try_to_vec().expect("failed to serialize");
+        Self::hash_bytes::<HF>(&serialized)
+    }
+}
+
+impl AsRef<[u8]> for CryptoHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for CryptoHash {
+    fn from(hash: [u8; 32]) -> Self {
+        CryptoHash(hash)
+    }
+}
+
+impl fmt::Display for CryptoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl fmt::Debug for CryptoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct MerklePathItem {
+    pub hash: CryptoHash,
+    pub direction: Direction,
+}
+
+pub type MerklePath = Vec<MerklePathItem>;
+
+/// Side of the sibling, relative to the node on the path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize)]
+pub enum Direction {
+    Left,
+    Right,
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use near_primitives::{hash::CryptoHash as NearCryptoHash, merkle as near_merkle};
+
+    use super::*;
+    use crate::test_utils::MockedHostFunctions;
+
+    #[test]
+    fn test_borsh_compatible_with_near() {
+        let path: MerklePath = [
+            MerklePathItem {
+                hash: CryptoHash([1; 32]),
+                direction: Direction::Left,
+            },
+            MerklePathItem {
+                hash: CryptoHash([2; 32]),
+                direction: Direction::Right,
+            },
+        ]
+        .into_iter()
+        .collect();
+        let near_path: near_merkle::MerklePath = [
+            near_merkle::MerklePathItem {
+                hash: NearCryptoHash([1; 32]),
+                direction: near_merkle::Direction::Left,
+            },
+            near_merkle::MerklePathItem {
+                hash: NearCryptoHash([2; 32]),
+                direction: near_merkle::Direction::Right,
+            },
+        ]
+        .into_iter()
+        .collect();
+
+        let serialized = path.try_to_vec().unwrap();
+        assert_eq!(serialized, near_path.try_to_vec().unwrap());
+        assert_eq!(MerklePath::try_from_slice(&serialized).unwrap(), path);
+    }
+
+    #[test]
+    fn test_hash_pair_matches_borsh() {
+        let (left, right) = (CryptoHash([1; 32]), CryptoHash([2; 32]));
+        assert_eq!(
+            CryptoHash::hash_pair::<MockedHostFunctions>(&left, &right),
+            CryptoHash::hash_borsh::<MockedHostFunctions, _>(&(left, right))
+        );
+        assert_eq!(
+            CryptoHash::hash_pair::<MockedHostFunctions>(&left, &right),
+            near_primitives::merkle::combine_hash(
+                &NearCryptoHash(left.0),
+                &NearCryptoHash(right.0)
+            )
+            .0
+            .into()
+        );
+    }
+}
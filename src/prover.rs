@@ -0,0 +1,175 @@
+//! Prover side: builds a tree out of all its leaves and emits proofs for them.
+//!
+//! The layout is the one of NEAR's `merklize`: leaves are paired level by level, and
+//! a node left without a sibling is carried up unchanged, so paths of leaves in an
+//! unbalanced tree can be shorter than the others.
+
+use core::marker::PhantomData;
+use std::vec::Vec;
+
+use borsh::BorshSerialize;
+
+use crate::{
+    BatchProofError, CryptoHash, Direction, HostFunctions, LeafIndex, MerklePath, MerklePathItem,
+    MultiProof,
+};
+
+/// A merkle tree holding every node, hashed with the given [`HostFunctions`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree<HF: HostFunctions> {
+    /// nodes of every level, from the leaves up to the root
+    levels: Vec<Vec<CryptoHash>>,
+    _hf: PhantomData<HF>,
+}
+
+impl<HF: HostFunctions> MerkleTree<HF> {
+    /// Builds the tree out of the hashes of its leaves.
+    pub fn from_leaf_hashes(leaf_hashes: Vec<CryptoHash>) -> Self {
+        let mut levels = Vec::new();
+        let mut level = leaf_hashes;
+        while level.len() > 1 {
+            let parents = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => CryptoHash::hash_pair::<HF>(left, right),
+                    // no sibling, the node is carried up as is
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(level);
+            level = parents;
+        }
+        levels.push(level);
+
+        Self {
+            levels,
+            _hf: PhantomData,
+        }
+    }
+
+    /// Builds the tree out of items, hashing the borsh serialization of each of them.
+    pub fn from_items<T: BorshSerialize>(items: &[T]) -> Self {
+        Self::from_leaf_hashes(items.iter().map(CryptoHash::hash_borsh::<HF, _>).collect())
+    }
+
+    /// Root of the tree. An empty tree has the default hash as its root.
+    pub fn root(&self) -> CryptoHash {
+        self.levels
+            .last()
+            .and_then(|level| level.first())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Number of leaves in the tree.
+    pub fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Merkle path of the leaf at `leaf_index`, from the leaf level up to the root.
+    pub fn path(&self, leaf_index: LeafIndex) -> Result<MerklePath, BatchProofError> {
+        if leaf_index >= self.len() {
+            return Err(BatchProofError::LeafIndexOutOfBounds {
+                leaf_index,
+                tree_size: self.len(),
+            });
+        }
+
+        let mut index = leaf_index;
+        let path = self
+            .levels
+            .iter()
+            .filter_map(|level| {
+                let sibling_index = index ^ 1;
+                let item = level.get(sibling_index).map(|hash| MerklePathItem {
+                    hash: *hash,
+                    direction: if sibling_index < index {
+                        Direction::Left
+                    } else {
+                        Direction::Right
+                    },
+                });
+                index /= 2;
+                item
+            })
+            .collect();
+        Ok(path)
+    }
+
+    /// Merkle paths of every leaf, in order.
+    pub fn paths(&self) -> Vec<MerklePath> {
+        (0..self.len())
+            .map(|leaf_index| self.path(leaf_index).expect("leaf index is in range"))
+            .collect()
+    }
+
+    /// Compact proof for the given subset of leaves, see [`MultiProof`].
+    pub fn multiproof(&self, leaf_indices: &[LeafIndex]) -> Result<MultiProof, BatchProofError> {
+        let paths = leaf_indices
+            .iter()
+            .map(|leaf_index| self.path(*leaf_index))
+            .collect::<Result<Vec<_>, _>>()?;
+        MultiProof::from_paths(paths.iter())
+    }
+}
+
+/// Same as NEAR's `merklize`: returns the root of the tree built out of `items`,
+/// along with the merkle path of every item.
+pub fn merklize<HF: HostFunctions, T: BorshSerialize>(
+    items: &[T],
+) -> (CryptoHash, Vec<MerklePath>) {
+    let tree = MerkleTree::<HF>::from_items(items);
+    (tree.root(), tree.paths())
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{item_hash, merklize as near_merklize, MockedHostFunctions};
+
+    #[test]
+    fn test_merklize_matches_near() {
+        for size in 0..=70u32 {
+            let elements = (0..size).collect::<Vec<_>>();
+            let (near_root, near_paths) = near_merklize(&elements);
+            let (root, paths) = merklize::<MockedHostFunctions, _>(&elements);
+            assert_eq!(root, near_root, "size {}", size);
+            assert_eq!(
+                paths.try_to_vec().unwrap(),
+                near_paths.try_to_vec().unwrap(),
+                "size {}",
+                size
+            );
+        }
+    }
+
+    #[test]
+    fn test_multiproof() {
+        let elements = (0..13u32).collect::<Vec<_>>();
+        let tree = MerkleTree::<MockedHostFunctions>::from_items(&elements);
+
+        let leaves = [0, 3, 4, 12];
+        let multiproof = tree.multiproof(&leaves).unwrap();
+        let leaf_hashes = leaves
+            .iter()
+            .map(|leaf| item_hash(&elements[*leaf]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            multiproof.verify::<MockedHostFunctions>(tree.root(), &leaf_hashes),
+            Ok(())
+        );
+
+        assert_eq!(
+            tree.multiproof(&[13]),
+            Err(BatchProofError::LeafIndexOutOfBounds {
+                leaf_index: 13,
+                tree_size: 13
+            })
+        );
+    }
+}
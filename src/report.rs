@@ -0,0 +1,87 @@
+//! Outcome of verifying a batch of proofs, see [`crate::ProofBatchVerifier::verify_batch`].
+
+use std::vec::Vec;
+
+use crate::{BatchProofError, CryptoHash, Index, Level};
+
+/// Outcome of verifying a single proof of a batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProofReport {
+    /// Root the proof lands on, if the whole path could be computed.
+    pub computed_root: Option<CryptoHash>,
+    /// Number of nodes that had to be hashed.
+    pub hashes_computed: usize,
+    /// Number of nodes that were served by the cache instead of being hashed.
+    pub hashes_cached: usize,
+    /// Why the proof was rejected, if it was.
+    pub error: Option<BatchProofError>,
+}
+
+impl ProofReport {
+    pub(crate) fn new(
+        result: Result<CryptoHash, BatchProofError>,
+        hashes_computed: usize,
+        hashes_cached: usize,
+    ) -> Self {
+        let (computed_root, error) = match result {
+            Ok(root) => (Some(root), None),
+            Err(error @ BatchProofError::RootMismatch { computed, .. }) => {
+                (Some(computed), Some(error))
+            }
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            computed_root,
+            hashes_computed,
+            hashes_cached,
+            error,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Coordinates of the node the proof disagreed on, if it failed because of one.
+    pub fn conflicting_node(&self) -> Option<(Level, Index)> {
+        match self.error {
+            Some(BatchProofError::CacheMismatch { level, index, .. })
+            | Some(BatchProofError::ConflictingProofs { level, index }) => Some((level, index)),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of verifying a batch of proofs, one [`ProofReport`] per input, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchReport {
+    pub proofs: Vec<ProofReport>,
+}
+
+impl BatchReport {
+    /// Whether every proof of the batch is valid.
+    pub fn is_valid(&self) -> bool {
+        self.proofs.iter().all(ProofReport::is_valid)
+    }
+
+    /// Reports of the proofs that were rejected, along with their position in the batch.
+    pub fn failures(&self) -> impl Iterator<Item = (usize, &ProofReport)> {
+        self.proofs
+            .iter()
+            .enumerate()
+            .filter(|(_, report)| !report.is_valid())
+    }
+
+    /// Total number of nodes hashed across the batch.
+    pub fn hashes_computed(&self) -> usize {
+        self.proofs
+            .iter()
+            .map(|report| report.hashes_computed)
+            .sum()
+    }
+
+    /// Total number of nodes served by the cache across the batch.
+    pub fn hashes_cached(&self) -> usize {
+        self.proofs.iter().map(|report| report.hashes_cached).sum()
+    }
+}
@@ -0,0 +1,284 @@
+//! Borsh persistence of a [`ProofBatchVerifier`], e.g. to survive a restart.
+//!
+//! A snapshot starts with a version byte, followed by the trusted root, the cached
+//! nodes and the siblings their proofs gave. Restoring checks the snapshot against
+//! the root the caller trusts, and revalidates every node against it, so a tampered
+//! snapshot cannot seed the cache.
+
+use core::marker::PhantomData;
+use std::string::ToString;
+
+use borsh::{
+    maybestd::io::{Error, ErrorKind, Result as IoResult, Write},
+    BorshDeserialize, BorshSerialize,
+};
+
+use crate::{
+    cache::{CacheSnapshot, CachedNodes},
+    BatchProofError, CryptoHash, HostFunctions, ProofBatchVerifier,
+};
+
+/// Version of the snapshot layout written by this crate.
+const SNAPSHOT_VERSION: u8 = 1;
+
+impl<HF: HostFunctions> ProofBatchVerifier<HF> {
+    /// Restores a verifier out of the borsh snapshot written by its
+    /// [`BorshSerialize`] implementation, for the tree with root `expected_root`.
+    ///
+    /// Fails with [`BatchProofError::RootMismatch`] if the snapshot was taken for
+    /// another root. Cached nodes are only kept if they hash up to `expected_root`
+    /// through the other nodes of the snapshot, or the siblings their proofs gave,
+    /// and the restored verifier is pinned
+    /// to it. A verifier that was not pinned yet holds no node, so its snapshot
+    /// restores as an empty verifier pinned to `expected_root`.
+    pub fn restore(mut bytes: &[u8], expected_root: CryptoHash) -> Result<Self, BatchProofError> {
+        let (stored_root, cache) = read_snapshot(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(BatchProofError::InvalidSnapshot);
+        }
+        match stored_root {
+            Some(stored_root) if stored_root != expected_root => {
+                Err(BatchProofError::RootMismatch {
+                    expected: expected_root,
+                    computed: stored_root,
+                })
+            }
+            _ => Self::from_snapshot(Some(expected_root), cache),
+        }
+    }
+
+    /// Rebuilds a verifier pinned to `trusted_root` out of the nodes of a snapshot.
+    fn from_snapshot(
+        trusted_root: Option<CryptoHash>,
+        cache: CacheSnapshot,
+    ) -> Result<Self, BatchProofError> {
+        Ok(Self {
+            cached_nodes: CachedNodes::restore::<HF>(cache, trusted_root)?,
+            trusted_root,
+            _hf: PhantomData,
+        })
+    }
+}
+
+/// Reads the version, trusted root and cached nodes of a snapshot.
+fn read_snapshot(buf: &mut &[u8]) -> Result<(Option<CryptoHash>, CacheSnapshot), BatchProofError> {
+    let version = u8::deserialize(buf).map_err(|_| BatchProofError::InvalidSnapshot)?;
+    if version != SNAPSHOT_VERSION {
+        return Err(BatchProofError::InvalidSnapshot);
+    }
+    <(Option<CryptoHash>, CacheSnapshot)>::deserialize(buf)
+        .map_err(|_| BatchProofError::InvalidSnapshot)
+}
+
+impl<HF: HostFunctions> BorshSerialize for ProofBatchVerifier<HF> {
+    fn serialize<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        SNAPSHOT_VERSION.serialize(writer)?;
+        self.trusted_root.serialize(writer)?;
+        self.cached_nodes.snapshot().serialize(writer)
+    }
+}
+
+/// Trusts the root stored in the snapshot: use [`ProofBatchVerifier::restore`] for
+/// snapshots read from storage that could have been tampered with.
+impl<HF: HostFunctions> BorshDeserialize for ProofBatchVerifier<HF> {
+    fn deserialize(buf: &mut &[u8]) -> IoResult<Self> {
+        let to_io_error = |e: BatchProofError| Error::new(ErrorKind::InvalidData, e.to_string());
+        let (trusted_root, cache) = read_snapshot(buf).map_err(to_io_error)?;
+        Self::from_snapshot(trusted_root, cache).map_err(to_io_error)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{item_hash, merklize, MockedHostFunctions},
+        CacheCapacity,
+    };
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+        let item_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+
+        let mut verifier =
+            ProofBatchVerifier::<MockedHostFunctions>::with_capacity(CacheCapacity::Entries(32));
+        verifier
+            .verify_all(
+                root_hash,
+                merkle_proofs.iter().zip(item_hashes.iter().copied()),
+            )
+            .unwrap();
+
+        let snapshot = verifier.try_to_vec().unwrap();
+        let restored =
+            ProofBatchVerifier::<MockedHostFunctions>::restore(&snapshot, root_hash).unwrap();
+        assert_eq!(restored.trusted_root(), Some(root_hash));
+        assert_eq!(restored.cached_nodes.inner, verifier.cached_nodes.inner);
+        assert_eq!(
+            restored.cached_nodes.path_item_cache_mapping,
+            verifier.cached_nodes.path_item_cache_mapping
+        );
+        assert_eq!(restored.try_to_vec().unwrap(), snapshot);
+        assert_eq!(restored.cached_nodes.capacity(), CacheCapacity::Entries(32));
+        assert_eq!(
+            ProofBatchVerifier::<MockedHostFunctions>::try_from_slice(&snapshot)
+                .unwrap()
+                .cached_nodes,
+            restored.cached_nodes
+        );
+
+        // the snapshot only consumes its own bytes, so it can be part of a larger value
+        let (nested, byte) = <(ProofBatchVerifier<MockedHostFunctions>, u8)>::try_from_slice(
+            &(verifier, 42u8).try_to_vec().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(nested.cached_nodes, restored.cached_nodes);
+        assert_eq!(byte, 42);
+        let mut trailing = snapshot.clone();
+        trailing.push(0);
+        assert_eq!(
+            ProofBatchVerifier::<MockedHostFunctions>::restore(&trailing, root_hash).err(),
+            Some(BatchProofError::InvalidSnapshot)
+        );
+
+        // a bound in bytes is restored as is, not as the number of entries it allows
+        let verifier =
+            ProofBatchVerifier::<MockedHostFunctions>::with_capacity(CacheCapacity::Bytes(4096));
+        let restored = ProofBatchVerifier::<MockedHostFunctions>::restore(
+            &verifier.try_to_vec().unwrap(),
+            root_hash,
+        )
+        .unwrap();
+        assert_eq!(restored.cached_nodes.capacity(), CacheCapacity::Bytes(4096));
+        assert_eq!(restored.trusted_root(), Some(root_hash));
+    }
+
+    /// Snapshots the verifier and restores it, checking that no node was dropped.
+    fn assert_roundtrip(verifier: &ProofBatchVerifier<MockedHostFunctions>, root_hash: CryptoHash) {
+        let snapshot = verifier.try_to_vec().unwrap();
+        let restored =
+            ProofBatchVerifier::<MockedHostFunctions>::restore(&snapshot, root_hash).unwrap();
+        assert_eq!(restored.cached_nodes.inner, verifier.cached_nodes.inner);
+        assert_eq!(
+            restored.cached_nodes.proven_siblings,
+            verifier.cached_nodes.proven_siblings
+        );
+        assert_eq!(restored.try_to_vec().unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip_partially_verified() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+
+        // the path of a single leaf, whose siblings are not cached
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        verifier
+            .verify(root_hash, &merkle_proofs[0], item_hash(&1))
+            .unwrap();
+        assert_eq!(verifier.cache_len(), 3);
+        assert_roundtrip(&verifier, root_hash);
+
+        verifier
+            .verify(root_hash, &merkle_proofs[6], item_hash(&7))
+            .unwrap();
+        assert_eq!(verifier.cache_len(), 5);
+        assert_roundtrip(&verifier, root_hash);
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip_unbalanced_tree() {
+        let elements = &[1, 2, 3, 4, 5];
+        let (root_hash, merkle_proofs) = merklize(elements);
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        verifier
+            .verify_all(
+                root_hash,
+                merkle_proofs.iter().zip(elements.iter().map(item_hash)),
+            )
+            .unwrap();
+        assert_eq!(verifier.cache_len(), 4);
+        assert_roundtrip(&verifier, root_hash);
+    }
+
+    #[test]
+    fn test_tampered_snapshot() {
+        let elements = &[1, 2, 3, 4, 5, 6, 7, 8];
+        let (root_hash, merkle_proofs) = merklize(elements);
+        let item_hashes = elements.iter().map(item_hash).collect::<Vec<_>>();
+
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        verifier
+            .verify_all(
+                root_hash,
+                merkle_proofs.iter().zip(item_hashes.iter().copied()),
+            )
+            .unwrap();
+
+        let snapshot = verifier.try_to_vec().unwrap();
+
+        // a forged node is dropped along with everything below it
+        verifier
+            .cached_nodes
+            .inner
+            .insert((1, 1), CryptoHash::default());
+        let restored = ProofBatchVerifier::<MockedHostFunctions>::restore(
+            &verifier.try_to_vec().unwrap(),
+            root_hash,
+        )
+        .unwrap();
+        let mut restored_nodes = restored.cached_nodes.inner.keys().collect::<Vec<_>>();
+        restored_nodes.sort();
+        assert_eq!(restored_nodes, [&(0, 0), &(1, 0), &(2, 0), &(2, 1)]);
+
+        // so is a node checked against a forged sibling
+        let mut verifier = ProofBatchVerifier::<MockedHostFunctions>::new();
+        verifier
+            .verify(root_hash, &merkle_proofs[0], item_hashes[0])
+            .unwrap();
+        verifier
+            .cached_nodes
+            .proven_siblings
+            .insert((1, 1), CryptoHash::default());
+        let restored = ProofBatchVerifier::<MockedHostFunctions>::restore(
+            &verifier.try_to_vec().unwrap(),
+            root_hash,
+        )
+        .unwrap();
+        assert_eq!(restored.cache_len(), 1);
+        assert!(restored.cached_nodes.proven_siblings.is_empty());
+
+        // a snapshot of another tree is consistent with its own root, but not ours
+        let (other_root, other_proofs) = merklize(&[9, 10, 11, 12]);
+        let mut other = ProofBatchVerifier::<MockedHostFunctions>::new();
+        other
+            .verify(other_root, &other_proofs[0], item_hash(&9))
+            .unwrap();
+        assert_eq!(
+            ProofBatchVerifier::<MockedHostFunctions>::restore(
+                &other.try_to_vec().unwrap(),
+                root_hash
+            )
+            .err(),
+            Some(BatchProofError::RootMismatch {
+                expected: root_hash,
+                computed: other_root
+            })
+        );
+        assert!(matches!(
+            ProofBatchVerifier::<MockedHostFunctions>::restore(&snapshot, CryptoHash::default()),
+            Err(BatchProofError::RootMismatch { .. })
+        ));
+
+        let mut snapshot = snapshot;
+        snapshot[0] = SNAPSHOT_VERSION + 1;
+        assert!(matches!(
+            ProofBatchVerifier::<MockedHostFunctions>::restore(&snapshot, root_hash),
+            Err(BatchProofError::InvalidSnapshot)
+        ));
+        assert!(ProofBatchVerifier::<MockedHostFunctions>::try_from_slice(&snapshot).is_err());
+    }
+}
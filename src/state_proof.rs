@@ -0,0 +1,698 @@
+//! Verification of NEAR state proofs against a state root.
+//!
+//! NEAR stores the state in a Patricia trie whose nodes are addressed by the hash
+//! of their serialization. A state proof is the list of the serialized nodes on the
+//! path of the keys it proves, and a key is absent when its path leaves the trie.
+//!
+//! Nodes only ever need to be hashed once: a node is identified by its hash, so
+//! [`StateProofVerifier`] caches the decoded nodes it verified and looks them up
+//! there before hashing anything, across keys, proofs and even state roots.
+
+use core::marker::PhantomData;
+use std::{boxed::Box, collections::HashMap, vec::Vec};
+
+use borsh::{
+    maybestd::io::{Error, ErrorKind, Result as IoResult, Write},
+    BorshDeserialize, BorshSerialize,
+};
+
+use crate::{BatchProofError, BatchReport, CryptoHash, HashCounts, HostFunctions, ProofReport};
+
+/// Length and hash of a value, which is stored out of the trie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct ValueRef {
+    pub length: u32,
+    pub hash: CryptoHash,
+}
+
+/// Children of a branch, one per nibble.
+pub type Children = [Option<CryptoHash>; 16];
+
+/// Node of the trie, borsh-compatible with NEAR's `RawTrieNode`.
+///
+/// The keys of leaves and extensions are nibbles in NEAR's compact encoding: the
+/// first byte holds flags, and the first nibble if there is an odd number of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RawTrieNode {
+    Leaf(Vec<u8>, ValueRef),
+    Branch(Box<Children>, Option<ValueRef>),
+    Extension(Vec<u8>, CryptoHash),
+}
+
+/// A trie node along with the memory usage of its subtrie, which is what gets
+/// hashed into the address of the node.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct RawTrieNodeWithSize {
+    pub node: RawTrieNode,
+    pub memory_usage: u64,
+}
+
+const LEAF_NODE: u8 = 0;
+const BRANCH_NODE_NO_VALUE: u8 = 1;
+const BRANCH_NODE_WITH_VALUE: u8 = 2;
+const EXTENSION_NODE: u8 = 3;
+
+impl BorshSerialize for RawTrieNode {
+    fn serialize<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        match self {
+            RawTrieNode::Leaf(key, value) => {
+                LEAF_NODE.serialize(writer)?;
+                key.serialize(writer)?;
+                value.serialize(writer)
+            }
+            RawTrieNode::Branch(children, None) => {
+                BRANCH_NODE_NO_VALUE.serialize(writer)?;
+                serialize_children(children, writer)
+            }
+            RawTrieNode::Branch(children, Some(value)) => {
+                BRANCH_NODE_WITH_VALUE.serialize(writer)?;
+                value.serialize(writer)?;
+                serialize_children(children, writer)
+            }
+            RawTrieNode::Extension(key, child) => {
+                EXTENSION_NODE.serialize(writer)?;
+                key.serialize(writer)?;
+                child.serialize(writer)
+            }
+        }
+    }
+}
+
+impl BorshDeserialize for RawTrieNode {
+    fn deserialize(buf: &mut &[u8]) -> IoResult<Self> {
+        match u8::deserialize(buf)? {
+            LEAF_NODE => Ok(RawTrieNode::Leaf(
+                Vec::deserialize(buf)?,
+                ValueRef::deserialize(buf)?,
+            )),
+            BRANCH_NODE_NO_VALUE => Ok(RawTrieNode::Branch(
+                Box::new(deserialize_children(buf)?),
+                None,
+            )),
+            BRANCH_NODE_WITH_VALUE => {
+                let value = ValueRef::deserialize(buf)?;
+                Ok(RawTrieNode::Branch(
+                    Box::new(deserialize_children(buf)?),
+                    Some(value),
+                ))
+            }
+            EXTENSION_NODE => Ok(RawTrieNode::Extension(
+                Vec::deserialize(buf)?,
+                CryptoHash::deserialize(buf)?,
+            )),
+            _ => Err(Error::new(ErrorKind::InvalidData, "unknown trie node kind")),
+        }
+    }
+}
+
+/// Children are written as a bitmap of the ones that are set, followed by their hashes.
+fn serialize_children<W: Write>(children: &Children, writer: &mut W) -> IoResult<()> {
+    let bitmap = children
+        .iter()
+        .enumerate()
+        .filter(|(_, child)| child.is_some())
+        .fold(0u16, |bitmap, (nibble, _)| bitmap | 1 << nibble);
+    bitmap.serialize(writer)?;
+    children
+        .iter()
+        .flatten()
+        .try_for_each(|child| child.serialize(writer))
+}
+
+fn deserialize_children(buf: &mut &[u8]) -> IoResult<Children> {
+    let bitmap = u16::deserialize(buf)?;
+    let mut children = [None; 16];
+    for (nibble, child) in children.iter_mut().enumerate() {
+        if bitmap & 1 << nibble != 0 {
+            *child = Some(CryptoHash::deserialize(buf)?);
+        }
+    }
+    Ok(children)
+}
+
+/// Nibbles of a key of a leaf or an extension, out of NEAR's compact encoding.
+fn decode_nibbles(encoded: &[u8]) -> Option<Vec<u8>> {
+    let (flags, rest) = encoded.split_first()?;
+    let mut nibbles = Vec::with_capacity(rest.len() * 2 + 1);
+    // an odd number of nibbles starts in the flags byte
+    if flags & 0x10 != 0 {
+        nibbles.push(flags & 0x0f);
+    }
+    rest.iter()
+        .for_each(|byte| nibbles.extend([byte >> 4, byte & 0x0f]));
+    Some(nibbles)
+}
+
+/// Nibbles of a key of the trie, the high one of each byte first.
+fn key_nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0f])
+        .collect()
+}
+
+/// Nodes of a proof, only hashed when a node missing from the cache is looked for.
+struct ProofNodes<'a, T> {
+    nodes: &'a [T],
+    /// number of nodes hashed so far, in order
+    hashed: usize,
+    positions: HashMap<CryptoHash, usize>,
+}
+
+impl<'a, T: AsRef<[u8]>> ProofNodes<'a, T> {
+    fn new(nodes: &'a [T]) -> Self {
+        Self {
+            nodes,
+            hashed: 0,
+            positions: HashMap::new(),
+        }
+    }
+
+    /// Serialized node with the given hash, hashing the nodes of the proof up to it.
+    fn find<HF: HostFunctions>(&mut self, hash: &CryptoHash) -> Option<&'a [u8]> {
+        // proofs list nodes from the root down, so the next one is usually it
+        while !self.positions.contains_key(hash) && self.hashed < self.nodes.len() {
+            let node = self.nodes[self.hashed].as_ref();
+            self.positions
+                .insert(CryptoHash::hash_bytes::<HF>(node), self.hashed);
+            self.hashed += 1;
+        }
+        let nodes = self.nodes;
+        self.positions
+            .get(hash)
+            .map(|position| nodes[*position].as_ref())
+    }
+}
+
+/// Next step of a lookup, out of the node it is at.
+enum Step {
+    Child(CryptoHash, usize),
+    Value(Option<ValueRef>),
+}
+
+/// Verifies the values of keys against a state root, out of state proofs.
+#[derive(Debug)]
+pub struct StateProofVerifier<HF: HostFunctions> {
+    state_root: CryptoHash,
+    /// decoded nodes, keyed by the hash of their serialization
+    nodes: HashMap<CryptoHash, RawTrieNode>,
+    _hf: PhantomData<HF>,
+}
+
+impl<HF: HostFunctions> StateProofVerifier<HF> {
+    pub fn new(state_root: CryptoHash) -> Self {
+        Self {
+            state_root,
+            nodes: HashMap::new(),
+            _hf: PhantomData,
+        }
+    }
+
+    /// Root the keys are looked up from.
+    pub fn state_root(&self) -> CryptoHash {
+        self.state_root
+    }
+
+    /// Moves to another state root. Nodes are addressed by their hash, so the cached
+    /// ones are kept, e.g. for the parts of the state that did not change.
+    pub fn set_state_root(&mut self, state_root: CryptoHash) {
+        self.state_root = state_root;
+    }
+
+    /// Number of trie nodes currently cached.
+    pub fn cache_len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Drops every cached node.
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+    }
+
+    /// Looks `key` up with the nodes of `proof`, returning its value if it has one.
+    ///
+    /// Fails if a node on the path of the key is neither cached nor in the proof,
+    /// so a key is only reported absent when the proof shows it is.
+    pub fn lookup<T: AsRef<[u8]>>(
+        &mut self,
+        proof: &[T],
+        key: &[u8],
+    ) -> Result<Option<ValueRef>, BatchProofError> {
+        self.lookup_with(&mut ProofNodes::new(proof), key, &mut HashCounts::default())
+    }
+
+    /// Verifies that `key` holds `value`, or that it has no value if `value` is `None`.
+    pub fn verify<T: AsRef<[u8]>>(
+        &mut self,
+        proof: &[T],
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) -> Result<(), BatchProofError> {
+        self.verify_with(
+            &mut ProofNodes::new(proof),
+            key,
+            value,
+            &mut HashCounts::default(),
+        )
+    }
+
+    /// Verifies every `(key, value)` pair with the nodes of a single proof, without
+    /// stopping at the first failure. Every node of the proof is hashed at most once.
+    ///
+    /// The report of a pair counts the nodes of the proof hashed while looking its
+    /// key up, and the nodes on its path found in the cache.
+    pub fn verify_batch<'a, T: AsRef<[u8]>>(
+        &mut self,
+        proof: &[T],
+        entries: impl IntoIterator<Item = (&'a [u8], Option<&'a [u8]>)>,
+    ) -> BatchReport {
+        let mut proof = ProofNodes::new(proof);
+        let proofs = entries
+            .into_iter()
+            .map(|(key, value)| {
+                let mut counts = HashCounts::default();
+                let result = self
+                    .verify_with(&mut proof, key, value, &mut counts)
+                    .map(|_| self.state_root);
+                ProofReport::new(result, counts.computed, counts.cached)
+            })
+            .collect();
+        BatchReport { proofs }
+    }
+
+    fn verify_with<T: AsRef<[u8]>>(
+        &mut self,
+        proof: &mut ProofNodes<'_, T>,
+        key: &[u8],
+        value: Option<&[u8]>,
+        counts: &mut HashCounts,
+    ) -> Result<(), BatchProofError> {
+        let found = self.lookup_with(proof, key, counts)?;
+        let expected = value.map(|value| ValueRef {
+            length: value.len() as u32,
+            hash: CryptoHash::hash_bytes::<HF>(value),
+        });
+        if found != expected {
+            return Err(BatchProofError::StateValueMismatch {
+                expected: expected.map(|value| value.hash),
+                found: found.map(|value| value.hash),
+            });
+        }
+        Ok(())
+    }
+
+    fn lookup_with<T: AsRef<[u8]>>(
+        &mut self,
+        proof: &mut ProofNodes<'_, T>,
+        key: &[u8],
+        counts: &mut HashCounts,
+    ) -> Result<Option<ValueRef>, BatchProofError> {
+        let nibbles = key_nibbles(key);
+        let (mut hash, mut position) = (self.state_root, 0);
+        // the empty trie has the default hash as its root
+        if hash == CryptoHash::default() {
+            return Ok(None);
+        }
+        loop {
+            let step = match self.node(proof, &hash, counts)? {
+                RawTrieNode::Leaf(leaf_key, value) => {
+                    let leaf_key = decode_nibbles(leaf_key)
+                        .ok_or(BatchProofError::MalformedTrieNode { hash })?;
+                    Step::Value(Some(*value).filter(|_| nibbles[position..] == leaf_key[..]))
+                }
+                RawTrieNode::Extension(extension_key, child) => {
+                    let extension_key = decode_nibbles(extension_key)
+                        .ok_or(BatchProofError::MalformedTrieNode { hash })?;
+                    if nibbles[position..].starts_with(&extension_key) {
+                        Step::Child(*child, position + extension_key.len())
+                    } else {
+                        Step::Value(None)
+                    }
+                }
+                RawTrieNode::Branch(_, value) if position == nibbles.len() => Step::Value(*value),
+                RawTrieNode::Branch(children, _) => match children[nibbles[position] as usize] {
+                    Some(child) => Step::Child(child, position + 1),
+                    None => Step::Value(None),
+                },
+            };
+            match step {
+                Step::Child(child, next_position) => {
+                    hash = child;
+                    position = next_position;
+                }
+                Step::Value(value) => return Ok(value),
+            }
+        }
+    }
+
+    /// Node with the given hash, out of the cache or else out of the proof.
+    fn node<T: AsRef<[u8]>>(
+        &mut self,
+        proof: &mut ProofNodes<'_, T>,
+        hash: &CryptoHash,
+        counts: &mut HashCounts,
+    ) -> Result<&RawTrieNode, BatchProofError> {
+        if self.nodes.contains_key(hash) {
+            counts.cached += 1;
+        } else {
+            let hashed = proof.hashed;
+            let bytes = proof.find::<HF>(hash);
+            counts.computed += proof.hashed - hashed;
+            let bytes = bytes.ok_or(BatchProofError::MissingTrieNode { hash: *hash })?;
+            let node = RawTrieNodeWithSize::try_from_slice(bytes)
+                .map_err(|_| BatchProofError::MalformedTrieNode { hash: *hash })?;
+            self.nodes.insert(*hash, node.node);
+        }
+        Ok(&self.nodes[hash])
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        count_sha256_calls, sha256_calls, CountingHostFunctions, MockedHostFunctions,
+    };
+
+    /// Nibbles in NEAR's compact encoding.
+    fn encode_nibbles(nibbles: &[u8], is_leaf: bool) -> Vec<u8> {
+        let odd = nibbles.len() % 2;
+        let mut flags = if is_leaf { 0x20 } else { 0 };
+        if odd == 1 {
+            flags |= 0x10 | nibbles[0];
+        }
+        let mut encoded = vec![flags];
+        encoded.extend(nibbles[odd..].chunks(2).map(|pair| pair[0] << 4 | pair[1]));
+        encoded
+    }
+
+    /// Builds the trie of the given entries, appending its serialized nodes to
+    /// `nodes` from the root down, and returns the hash of its root.
+    fn build(entries: &[(Vec<u8>, Vec<u8>)], nodes: &mut Vec<Vec<u8>>) -> CryptoHash {
+        let value_ref = |value: &Vec<u8>| ValueRef {
+            length: value.len() as u32,
+            hash: CryptoHash::hash_bytes::<MockedHostFunctions>(value),
+        };
+        let position = nodes.len();
+        let prefix_len = match entries {
+            [_] => 0,
+            [(first, _), rest @ ..] => rest.iter().fold(first.len(), |len, (nibbles, _)| {
+                first
+                    .iter()
+                    .zip(nibbles)
+                    .take(len)
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            }),
+            [] => unreachable!(),
+        };
+        let node = match entries {
+            [(nibbles, value)] => {
+                RawTrieNode::Leaf(encode_nibbles(nibbles, true), value_ref(value))
+            }
+            _ if prefix_len > 0 => {
+                let stripped = entries
+                    .iter()
+                    .map(|(nibbles, value)| (nibbles[prefix_len..].to_vec(), value.clone()))
+                    .collect::<Vec<_>>();
+                nodes.push(Vec::new());
+                RawTrieNode::Extension(
+                    encode_nibbles(&entries[0].0[..prefix_len], false),
+                    build(&stripped, nodes),
+                )
+            }
+            _ => {
+                nodes.push(Vec::new());
+                let mut children = [None; 16];
+                for (nibble, child) in children.iter_mut().enumerate() {
+                    let stripped = entries
+                        .iter()
+                        .filter(|(nibbles, _)| nibbles.first() == Some(&(nibble as u8)))
+                        .map(|(nibbles, value)| (nibbles[1..].to_vec(), value.clone()))
+                        .collect::<Vec<_>>();
+                    if !stripped.is_empty() {
+                        *child = Some(build(&stripped, nodes));
+                    }
+                }
+                let value = entries
+                    .iter()
+                    .find(|(nibbles, _)| nibbles.is_empty())
+                    .map(|(_, value)| value_ref(value));
+                RawTrieNode::Branch(Box::new(children), value)
+            }
+        };
+        let serialized = RawTrieNodeWithSize {
+            node,
+            memory_usage: entries.len() as u64,
+        }
+        .try_to_vec()
+        .unwrap();
+        let hash = CryptoHash::hash_bytes::<MockedHostFunctions>(&serialized);
+        match nodes.get_mut(position) {
+            Some(placeholder) => *placeholder = serialized,
+            None => nodes.push(serialized),
+        }
+        hash
+    }
+
+    fn state() -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = [
+            &b"alice"[..],
+            b"alice.near",
+            b"alina",
+            b"bob",
+            b"bobby",
+            b"carol",
+            b"x",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, key)| (key.to_vec(), vec![i as u8; i + 1]))
+        .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    }
+
+    fn trie(entries: &[(Vec<u8>, Vec<u8>)]) -> (CryptoHash, Vec<Vec<u8>>) {
+        let with_nibbles = entries
+            .iter()
+            .map(|(key, value)| (key_nibbles(key), value.clone()))
+            .collect::<Vec<_>>();
+        let mut nodes = Vec::new();
+        let root = build(&with_nibbles, &mut nodes);
+        (root, nodes)
+    }
+
+    #[test]
+    fn test_node_layout() {
+        let leaf = RawTrieNodeWithSize {
+            node: RawTrieNode::Leaf(
+                vec![1, 2, 3],
+                ValueRef {
+                    length: 42,
+                    hash: CryptoHash([7; 32]),
+                },
+            ),
+            memory_usage: 5,
+        };
+        let mut expected = vec![0, 3, 0, 0, 0, 1, 2, 3, 42, 0, 0, 0];
+        expected.extend([7; 32]);
+        expected.extend([5, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(leaf.try_to_vec().unwrap(), expected);
+
+        let mut children = [None; 16];
+        children[3] = Some(CryptoHash([1; 32]));
+        children[15] = Some(CryptoHash([2; 32]));
+        let branch = RawTrieNodeWithSize {
+            node: RawTrieNode::Branch(Box::new(children), None),
+            memory_usage: 1,
+        };
+        let serialized = branch.try_to_vec().unwrap();
+        assert_eq!(serialized[..3], [1, 0b1000, 0b1000_0000]);
+        assert_eq!(serialized.len(), 3 + 64 + 8);
+        assert_eq!(
+            RawTrieNodeWithSize::try_from_slice(&serialized).unwrap(),
+            branch
+        );
+        assert!(RawTrieNodeWithSize::try_from_slice(&[4, 0, 0]).is_err());
+
+        assert_eq!(
+            decode_nibbles(&encode_nibbles(&[1, 2, 3], true)).unwrap(),
+            [1, 2, 3]
+        );
+        assert_eq!(
+            decode_nibbles(&encode_nibbles(&[1, 2], false)).unwrap(),
+            [1, 2]
+        );
+    }
+
+    /// Same vectors as the encoding test of `RawTrieNodeWithSize` in nearcore's
+    /// `core/store/src/trie/raw_node.rs`, which `near-primitives` does not export.
+    #[test]
+    fn test_node_layout_matches_nearcore() {
+        fn check(node: RawTrieNode, encoded: &[u8]) {
+            let node = RawTrieNodeWithSize {
+                node,
+                memory_usage: 42,
+            };
+            assert_eq!(node.try_to_vec().unwrap(), encoded);
+            assert_eq!(RawTrieNodeWithSize::try_from_slice(encoded).unwrap(), node);
+        }
+
+        let value = ValueRef {
+            length: 3,
+            hash: CryptoHash::hash_bytes::<MockedHostFunctions>(&[123, 245, 255]),
+        };
+        let value_hash = [
+            194, 40, 8, 24, 64, 219, 69, 132, 86, 52, 110, 175, 57, 198, 165, 200, 83, 237, 211,
+            11, 194, 83, 251, 33, 145, 138, 234, 226, 7, 242, 186, 73,
+        ];
+        let memory_usage = [42, 0, 0, 0, 0, 0, 0, 0];
+        let encoded = |parts: &[&[u8]]| parts.concat();
+
+        check(
+            RawTrieNode::Leaf(vec![1, 2, 3], value),
+            &encoded(&[
+                &[0, 3, 0, 0, 0, 1, 2, 3, 3, 0, 0, 0],
+                &value_hash,
+                &memory_usage,
+            ]),
+        );
+
+        let mut children = [None; 16];
+        children[3] = Some(CryptoHash([1; 32]));
+        check(
+            RawTrieNode::Branch(Box::new(children), Some(value)),
+            &encoded(&[
+                &[2, 3, 0, 0, 0],
+                &value_hash,
+                &[8, 0],
+                &[1; 32],
+                &memory_usage,
+            ]),
+        );
+        check(
+            RawTrieNode::Branch(Box::new(children), None),
+            &encoded(&[&[1, 8, 0], &[1; 32], &memory_usage]),
+        );
+
+        check(
+            RawTrieNode::Extension(vec![123, 245, 255], CryptoHash::default()),
+            &encoded(&[&[3, 3, 0, 0, 0, 123, 245, 255], &[0; 32], &memory_usage]),
+        );
+    }
+
+    #[test]
+    fn test_membership_and_absence() {
+        let entries = state();
+        let (root, nodes) = trie(&entries);
+
+        let mut verifier = StateProofVerifier::<MockedHostFunctions>::new(root);
+        for (key, value) in entries.iter() {
+            verifier.verify(&nodes, key, Some(value)).unwrap();
+        }
+        for key in [&b"ali"[..], b"alice.", b"bo", b"dave", b"", b"xx"] {
+            verifier.verify(&nodes, key, None).unwrap();
+        }
+        assert_eq!(verifier.cache_len(), nodes.len());
+
+        assert_eq!(
+            verifier.verify(&nodes, b"bob", Some(b"eve")),
+            Err(BatchProofError::StateValueMismatch {
+                expected: Some(CryptoHash::hash_bytes::<MockedHostFunctions>(b"eve")),
+                found: Some(CryptoHash::hash_bytes::<MockedHostFunctions>(&entries[3].1)),
+            })
+        );
+        assert!(matches!(
+            verifier.verify(&nodes, b"dave", Some(b"1")),
+            Err(BatchProofError::StateValueMismatch { found: None, .. })
+        ));
+
+        // the empty trie holds nothing
+        let mut empty = StateProofVerifier::<MockedHostFunctions>::new(CryptoHash::default());
+        assert_eq!(empty.lookup::<Vec<u8>>(&[], b"alice"), Ok(None));
+    }
+
+    #[test]
+    fn test_incomplete_and_forged_proofs() {
+        let entries = state();
+        let (root, nodes) = trie(&entries);
+
+        // absence cannot be shown without the nodes on the path
+        let mut verifier = StateProofVerifier::<MockedHostFunctions>::new(root);
+        assert!(matches!(
+            verifier.verify(&nodes[..1], b"dave", None),
+            Err(BatchProofError::MissingTrieNode { .. })
+        ));
+        verifier.verify(&nodes[..2], b"dave", None).unwrap();
+        assert!(matches!(
+            verifier.verify::<Vec<u8>>(&[], b"alice", None),
+            Err(BatchProofError::MissingTrieNode { .. })
+        ));
+
+        // a node changed to hide a key no longer hashes to its address
+        let mut verifier = StateProofVerifier::<MockedHostFunctions>::new(root);
+        let mut forged = nodes.clone();
+        let last = forged.len() - 1;
+        forged[last][1] ^= 1;
+        let report = verifier.verify_batch(
+            &forged,
+            entries
+                .iter()
+                .map(|(key, value)| (&key[..], Some(&value[..]))),
+        );
+        assert!(report.failures().any(|(_, report)| matches!(
+            report.error,
+            Some(BatchProofError::MissingTrieNode { .. })
+        )));
+        assert!(report.failures().count() < entries.len());
+
+        let malformed = vec![vec![9u8, 9, 9]];
+        let mut verifier =
+            StateProofVerifier::<MockedHostFunctions>::new(CryptoHash::hash_bytes::<
+                MockedHostFunctions,
+            >(&malformed[0]));
+        assert!(matches!(
+            verifier.verify(&malformed, b"alice", None),
+            Err(BatchProofError::MalformedTrieNode { .. })
+        ));
+    }
+
+    #[test]
+    fn test_nodes_are_hashed_once() {
+        let entries = state();
+        let (root, nodes) = trie(&entries);
+
+        let mut verifier = StateProofVerifier::<CountingHostFunctions>::new(root);
+        let _counting = count_sha256_calls();
+        let report = verifier.verify_batch(
+            &nodes,
+            entries
+                .iter()
+                .map(|(key, value)| (&key[..], Some(&value[..]))),
+        );
+        assert!(report.is_valid());
+        assert!(report
+            .proofs
+            .iter()
+            .all(|report| report.computed_root == Some(root)));
+        // every node once, and every value
+        assert_eq!(sha256_calls(), nodes.len() + entries.len());
+        assert_eq!(report.hashes_computed(), nodes.len());
+        // the root is hashed by the first key, and served by the cache afterwards
+        assert_eq!(report.proofs[0].hashes_cached, 0);
+        assert!(report.proofs[1..]
+            .iter()
+            .all(|report| report.hashes_cached > 0));
+
+        // the cache survives a new root: only the nodes above the removed key changed
+        let (new_root, new_nodes) = trie(&entries[1..]);
+        verifier.set_state_root(new_root);
+        let calls = sha256_calls();
+        verifier
+            .verify(&new_nodes, b"carol", Some(&entries[5].1))
+            .unwrap();
+        // the root and the branch of the first nibble, then the value
+        assert_eq!(sha256_calls() - calls, 3);
+        verifier.verify(&new_nodes, &entries[0].0, None).unwrap();
+    }
+}
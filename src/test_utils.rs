@@ -0,0 +1,99 @@
+//! Helpers shared by the tests of every module.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    sync::{Mutex, MutexGuard},
+    vec::Vec,
+};
+
+use borsh::BorshSerialize;
+use near_primitives::merkle as near_merkle;
+
+use crate::{CryptoHash, Direction, HostFunctions, MerklePath, MerklePathItem};
+
+pub(crate) struct MockedHostFunctions;
+impl HostFunctions for MockedHostFunctions {
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        use sha2::Digest;
+        sha2::Sha256::digest(data).into()
+    }
+
+    fn ed25519_verify(signature: &[u8; 64], message: &[u8], public_key: &[u8; 32]) -> bool {
+        use near_crypto::{ED25519PublicKey, KeyType, PublicKey, Signature};
+        let public_key = PublicKey::ED25519(ED25519PublicKey(*public_key));
+        Signature::from_parts(KeyType::ED25519, signature)
+            .map(|signature| signature.verify(message, &public_key))
+            .unwrap_or(false)
+    }
+}
+
+static SHA256_CALLS: AtomicUsize = AtomicUsize::new(0);
+static COUNTING: Mutex<()> = Mutex::new(());
+
+/// Host functions that count how many times `sha256` is called, on any thread.
+/// See [`count_sha256_calls`].
+pub(crate) struct CountingHostFunctions;
+impl HostFunctions for CountingHostFunctions {
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        SHA256_CALLS.fetch_add(1, Ordering::SeqCst);
+        MockedHostFunctions::sha256(data)
+    }
+}
+
+/// Resets the count of [`CountingHostFunctions`]. Tests run concurrently, so the
+/// other tests counting calls wait until the returned guard is dropped.
+pub(crate) fn count_sha256_calls() -> MutexGuard<'static, ()> {
+    // a failed test holding the guard does not make the count unreliable
+    let guard = COUNTING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    SHA256_CALLS.store(0, Ordering::SeqCst);
+    guard
+}
+
+/// Number of calls to [`CountingHostFunctions::sha256`] since [`count_sha256_calls`].
+pub(crate) fn sha256_calls() -> usize {
+    SHA256_CALLS.load(Ordering::SeqCst)
+}
+
+/// Hashes an item the same way NEAR's `merklize` does.
+pub(crate) fn item_hash<T: BorshSerialize>(value: &T) -> CryptoHash {
+    CryptoHash::hash_borsh::<MockedHostFunctions, _>(value)
+}
+
+/// Builds a tree with NEAR's `merklize`, converted to this crate's primitives.
+pub(crate) fn merklize<T: BorshSerialize>(arr: &[T]) -> (CryptoHash, Vec<MerklePath>) {
+    let (root_hash, paths) = near_merkle::merklize(arr);
+    let paths = paths
+        .iter()
+        .map(|path| {
+            path.iter()
+                .map(|item| MerklePathItem {
+                    hash: CryptoHash(item.hash.0),
+                    direction: match item.direction {
+                        near_merkle::Direction::Left => Direction::Left,
+                        near_merkle::Direction::Right => Direction::Right,
+                    },
+                })
+                .collect()
+        })
+        .collect();
+    (CryptoHash(root_hash.0), paths)
+}
+
+pub(crate) fn compute_root_from_path_and_item<T: BorshSerialize>(
+    path: &MerklePath,
+    item: &T,
+) -> CryptoHash {
+    let path = path
+        .iter()
+        .map(|item| near_merkle::MerklePathItem {
+            hash: near_primitives::hash::CryptoHash(item.hash.0),
+            direction: match item.direction {
+                Direction::Left => near_merkle::Direction::Left,
+                Direction::Right => near_merkle::Direction::Right,
+            },
+        })
+        .collect();
+    CryptoHash(near_merkle::compute_root_from_path_and_item(&path, item).0)
+}